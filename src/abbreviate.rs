@@ -0,0 +1,69 @@
+use serde_json::{Map, Value};
+
+/// Home Assistant's abbreviations for the discovery keys this daemon uses.
+const ABBREVIATIONS: &[(&str, &str)] = &[
+    ("availability_topic", "avty_t"),
+    ("command_topic", "cmd_t"),
+    ("components", "cmps"),
+    ("device", "dev"),
+    ("device_class", "dev_cla"),
+    ("enabled_by_default", "en"),
+    ("entity_category", "ent_cat"),
+    ("expire_after", "exp_aft"),
+    ("force_update", "frc_upd"),
+    ("icon", "ic"),
+    ("identifiers", "ids"),
+    ("json_attributes_topic", "json_attr_t"),
+    ("manufacturer", "mf"),
+    ("model", "mdl"),
+    ("options", "ops"),
+    ("origin", "o"),
+    ("platform", "p"),
+    ("serial_number", "sn"),
+    ("state_topic", "stat_t"),
+    ("suggested_display_precision", "sug_dsp_prc"),
+    ("support_url", "url"),
+    ("sw_version", "sw"),
+    ("unique_id", "uniq_id"),
+    ("unit_of_measurement", "unit_of_meas"),
+    ("value_template", "val_tpl"),
+];
+
+fn abbreviation(key: &str) -> Option<&'static str> {
+    ABBREVIATIONS
+        .iter()
+        .find(|(full, _)| *full == key)
+        .map(|(_, short)| *short)
+}
+
+/// Replaces every key of a discovery config with its abbreviation. The keys
+/// of `components` are object ids and are left alone.
+pub(crate) fn abbreviate(value: Value) -> Value {
+    let Value::Object(map) = value else {
+        return value;
+    };
+    let mut short = Map::new();
+    for (key, value) in map {
+        let value = match (key.as_str(), value) {
+            ("components", Value::Object(components)) => Value::Object(
+                components
+                    .into_iter()
+                    .map(|(id, component)| (id, abbreviate(component)))
+                    .collect(),
+            ),
+            (_, value) => abbreviate(value),
+        };
+        let key = abbreviation(&key).map_or(key, String::from);
+        short.insert(key, value);
+    }
+    Value::Object(short)
+}
+
+/// The abbreviated form of a serialized discovery config, or the config as
+/// is if it is not JSON.
+pub(crate) fn abbreviate_payload(payload: String) -> String {
+    match serde_json::from_str(&payload) {
+        Ok(value) => abbreviate(value).to_string(),
+        Err(_) => payload,
+    }
+}
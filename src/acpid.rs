@@ -0,0 +1,29 @@
+use anyhow::{Context, Result};
+use std::{path::Path, sync::Arc};
+use tokio::{
+    io::{AsyncBufReadExt, BufReader},
+    net::UnixStream,
+    sync::Notify,
+};
+
+const SOCKET: &str = "/var/run/acpid.socket";
+
+pub(crate) fn available() -> bool {
+    Path::new(SOCKET).exists()
+}
+
+/// Wakes `refresh` on every AC adapter and battery event acpid reports, e.g.
+/// "ac_adapter ACPI0003:00 00000080 00000000", so plugging in the charger is
+/// published right away.
+pub(crate) async fn watch(refresh: Arc<Notify>) -> Result<()> {
+    let stream = UnixStream::connect(SOCKET)
+        .await
+        .with_context(|| format!("connecting to {}", SOCKET))?;
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.starts_with("ac_adapter") || line.starts_with("battery") {
+            refresh.notify_one();
+        }
+    }
+    Ok(())
+}
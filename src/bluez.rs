@@ -0,0 +1,63 @@
+use crate::peripherals::{self, Peripheral, PeripheralState};
+use anyhow::{Context, Result};
+use zbus::{fdo::ObjectManagerProxy, Connection};
+
+const SERVICE: &str = "org.bluez";
+
+/// Bluetooth devices that report their charge through BlueZ's `Battery1`
+/// interface, i.e. connected headphones, keyboards and the like.
+pub(crate) async fn peripherals() -> Result<Vec<Peripheral>> {
+    let conn = Connection::system()
+        .await
+        .context("connecting to the system bus")?;
+    let manager = ObjectManagerProxy::builder(&conn)
+        .destination(SERVICE)?
+        .path("/")?
+        .build()
+        .await?;
+    let objects = manager
+        .get_managed_objects()
+        .await
+        .context("listing BlueZ devices")?;
+    let mut peripherals = Vec::new();
+    for interfaces in objects.values() {
+        let interface = |name: &str| {
+            interfaces
+                .iter()
+                .find(|(interface, _)| interface.as_str() == name)
+                .map(|(_, properties)| properties)
+        };
+        let (Some(battery), Some(device)) = (
+            interface("org.bluez.Battery1"),
+            interface("org.bluez.Device1"),
+        ) else {
+            continue;
+        };
+        let Some(percentage) = battery
+            .get("Percentage")
+            .and_then(|value| value.downcast_ref::<u8>().ok())
+        else {
+            continue;
+        };
+        let text = |key: &str| {
+            device
+                .get(key)
+                .and_then(|value| value.downcast_ref::<&str>().ok())
+                .map(String::from)
+        };
+        let Some(address) = text("Address") else {
+            continue;
+        };
+        peripherals.push(Peripheral {
+            id: peripherals::id("bt", &address),
+            name: text("Alias")
+                .or_else(|| text("Name"))
+                .unwrap_or_else(|| address.clone()),
+            state: PeripheralState {
+                percentage: f32::from(percentage),
+                status: None,
+            },
+        });
+    }
+    Ok(peripherals)
+}
@@ -0,0 +1,147 @@
+use crate::{heartbeat, poll_interval, Args};
+
+/// Problems with a topic the daemon publishes to.
+fn topic_problems(topic: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    if topic.is_empty() {
+        problems.push(String::from("is empty"));
+    }
+    if topic.len() > usize::from(u16::MAX) {
+        problems.push(format!("is {} bytes long, at most 65535 fit", topic.len()));
+    }
+    if topic.contains(['+', '#']) {
+        problems.push(String::from(
+            "contains the wildcard + or #, which only subscriptions may use",
+        ));
+    }
+    if topic.contains('\0') {
+        problems.push(String::from("contains a NUL character"));
+    }
+    if topic.starts_with('$') {
+        problems.push(String::from(
+            "starts with $, which brokers reserve for their own topics",
+        ));
+    }
+    if topic.ends_with('/') {
+        problems.push(String::from(
+            "ends with /, which leaves an empty level before each suffix",
+        ));
+    }
+    problems
+}
+
+/// Problems with the polling intervals, heartbeat and thresholds.
+fn timing_problems(args: &Args) -> Vec<String> {
+    let mut problems = Vec::new();
+    let interval = poll_interval(args).as_secs();
+    let heartbeat = heartbeat(args).as_secs();
+    if interval > heartbeat {
+        problems.push(format!(
+            "--interval {}s is longer than --heartbeat {}s, so the battery is read every heartbeat; \
+             lower --interval or raise --heartbeat",
+            interval, heartbeat
+        ));
+    }
+    if let Some(fast) = args.fast_interval.filter(|fast| *fast >= interval) {
+        problems.push(format!(
+            "--fast-interval {}s is not shorter than --interval {}s; lower it or drop it",
+            fast, interval
+        ));
+    }
+    if let Some(slow) = args.slow_interval {
+        if slow <= interval {
+            problems.push(format!(
+                "--slow-interval {}s is not longer than --interval {}s; raise it or drop it",
+                slow, interval
+            ));
+        }
+        if slow > heartbeat {
+            problems.push(format!(
+                "--slow-interval {}s is capped at --heartbeat {}s; lower it or raise --heartbeat",
+                slow, heartbeat
+            ));
+        }
+    }
+    if let Some(schedule) = &args.schedule {
+        match schedule.longest_gap(8) {
+            None => problems.push(String::from(
+                "--schedule never runs; check the day and month fields",
+            )),
+            Some(gap) if gap.as_secs() > heartbeat => problems.push(format!(
+                "--schedule runs up to {}s apart, longer than --heartbeat {}s, so heartbeats \
+                 are published in between; raise --heartbeat to publish only on schedule",
+                gap.as_secs(),
+                heartbeat
+            )),
+            Some(_) => {}
+        }
+    }
+    if !(0.0..=100.0).contains(&args.min_change) {
+        problems.push(format!(
+            "--min-change {} is not a percentage between 0 and 100",
+            args.min_change
+        ));
+    }
+    if let Some(limit) = args.charge_limit.filter(|limit| *limit <= args.fast_below) {
+        problems.push(format!(
+            "--charge-limit {}% is at or below --fast-below {}%, so a battery held at the \
+             limit is read fast; lower --fast-below",
+            limit, args.fast_below
+        ));
+    }
+    for (key, sensor) in &args.settings.sensors {
+        if sensor.period == Some(0) {
+            problems.push(format!("sensors.{} has a period of 0 seconds", key));
+        }
+    }
+    for external in &args.settings.external {
+        if external.command.is_empty() {
+            problems.push(format!("external {:?} has no command", external.name));
+        }
+        if external.interval == 0 {
+            problems.push(format!(
+                "external {:?} has an interval of 0 seconds",
+                external.name
+            ));
+        }
+    }
+    problems
+}
+
+/// Prints `[ok]` or `[fail]` with the problems, returning whether there were
+/// none.
+fn report(what: &str, problems: Vec<String>) -> bool {
+    if problems.is_empty() {
+        println!("[ok]   {}", what);
+        return true;
+    }
+    println!("[fail] {}", what);
+    for problem in problems {
+        println!("         {}", problem);
+    }
+    false
+}
+
+/// Validates the options, config file included, without reading the battery
+/// or connecting to the broker, returning whether everything passed.
+pub(crate) fn run(args: &Args) -> bool {
+    let mut passed = true;
+    // Loading already failed otherwise.
+    for path in &args.settings.files {
+        passed &= report(&format!("config {}", path.display()), Vec::new());
+    }
+    passed &= report(
+        &format!("topic {}", args.topic),
+        topic_problems(&args.topic),
+    );
+    if !args.no_discovery {
+        passed &= report(
+            &format!("discovery topic {}", args.discovery_topic),
+            topic_problems(&args.discovery_topic),
+        );
+    }
+    passed &= report("intervals and thresholds", timing_problems(args));
+    let outcome = if passed { "passed" } else { "failed" };
+    println!("check {}", outcome);
+    passed
+}
@@ -0,0 +1,557 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// The system-wide config file, which the user's overrides.
+const SYSTEM_PATH: &str = "/etc/battery-monitor-daemon/config.toml";
+
+/// Broker settings the system config file locks whenever it sets them, so
+/// managed machines keep publishing where the admin says.
+const BROKER_KEYS: &[&str] = &["hostname", "port", "username", "password_file", "keyring"];
+
+/// What `locked = ["*"]` leaves the files after it to set: the intervals and
+/// which sensors are published.
+const ADJUSTABLE_KEYS: &[&str] = &[
+    "interval",
+    "fast_interval",
+    "fast_below",
+    "slow_interval",
+    "heartbeat",
+    "schedule",
+    "align",
+    "min_change",
+    "include_sensor",
+    "exclude_sensor",
+    "sensors",
+];
+
+/// Options that say how to run this once rather than what to publish, which
+/// `locked = ["*"]` leaves alone on the command line.
+const INVOCATION_KEYS: &[&str] = &[
+    "config",
+    "profile",
+    "verbose",
+    "quiet",
+    "log_level",
+    "log_format",
+    "dry_run",
+    "print_discovery",
+];
+
+/// Where the user's config file goes: `$XDG_CONFIG_HOME`, or `~/.config`,
+/// then `battery-monitor-daemon/config.toml`.
+pub(crate) fn user_path() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("battery-monitor-daemon").join("config.toml"))
+}
+
+/// The config files read, system first, that exist: the system file, then
+/// `config` or else the user's. The system file is read even with `config`,
+/// so the keys it locks stay locked.
+pub(crate) fn paths(config: Option<&Path>) -> Vec<PathBuf> {
+    let system = PathBuf::from(SYSTEM_PATH);
+    let own = match config {
+        Some(config) if config == system => None,
+        Some(config) => Some(config.to_path_buf()),
+        None => user_path().filter(|path| path.is_file()),
+    };
+    std::iter::once(system)
+        .filter(|path| path.is_file())
+        .chain(own)
+        .collect()
+}
+
+/// Merges `over` into `base`: tables key by key, anything else replaced.
+fn merge(base: &mut toml::Table, over: toml::Table) {
+    for (key, value) in over {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(over)) => merge(base, over),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Settings read from the `--config` TOML file, or the system and user
+/// config files.
+#[derive(Deserialize, Default, Clone)]
+pub(crate) struct Config {
+    /// Command line options by their long name, e.g. `discovery_topic =
+    /// "homeassistant"`, `icon = ["percentage=mdi:laptop"]` or `hid = true`.
+    #[serde(flatten)]
+    pub(crate) options: BTreeMap<String, toml::Value>,
+    /// Discovery overrides keyed by sensor, e.g. `[sensors.energy_rate]`, or
+    /// `[sensors.percentage_bat1]` for the entity of a single battery.
+    #[serde(default)]
+    pub(crate) sensors: BTreeMap<String, SensorOverride>,
+    /// How the charge of several batteries is combined.
+    #[serde(default)]
+    pub(crate) aggregate: Aggregate,
+    #[serde(default)]
+    pub(crate) peripherals: PeripheralFilter,
+    /// Batteries read by running a command, e.g. `[[external]]` tables for
+    /// phones.
+    #[serde(default)]
+    pub(crate) external: Vec<ExternalSource>,
+    /// Outputs published to side by side, e.g. `[profiles.metrics]`, each
+    /// with these settings overridden by its own table, like another broker,
+    /// credentials, topic or sensor overrides.
+    #[serde(default)]
+    pub(crate) profiles: BTreeMap<String, toml::Table>,
+    /// The files these settings were read from.
+    #[serde(skip)]
+    pub(crate) files: Vec<PathBuf>,
+    /// The merged files, which profiles override.
+    #[serde(skip)]
+    table: toml::Table,
+    /// Keys the files locked against everything after them.
+    #[serde(skip)]
+    locks: Locks,
+}
+
+/// A battery whose charge is read from the JSON a command prints.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ExternalSource {
+    /// Shown in Home Assistant and used for the entity's id.
+    pub(crate) name: String,
+    /// Program and arguments, run without a shell, e.g.
+    /// `["ssh", "phone", "termux-battery-status"]`.
+    pub(crate) command: Vec<String>,
+    /// Seconds between runs.
+    #[serde(default = "default_external_interval")]
+    pub(crate) interval: u64,
+    /// Key of the charge in percent in the command's output.
+    #[serde(default = "default_percentage_key")]
+    pub(crate) percentage_key: String,
+    /// Key of an optional charging status, e.g. "CHARGING".
+    #[serde(default = "default_status_key")]
+    pub(crate) status_key: String,
+}
+
+fn default_external_interval() -> u64 {
+    300
+}
+
+fn default_percentage_key() -> String {
+    String::from("percentage")
+}
+
+fn default_status_key() -> String {
+    String::from("status")
+}
+
+/// Picks which peripherals get published, matching each pattern as a
+/// case-insensitive substring of the peripheral's name or id. Everything is
+/// included unless `include` is given; `exclude` wins over `include`.
+#[derive(Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PeripheralFilter {
+    /// Same as `--peripherals`, whose name the table takes.
+    #[serde(default)]
+    pub(crate) upower: bool,
+    #[serde(default)]
+    pub(crate) include: Vec<String>,
+    #[serde(default)]
+    pub(crate) exclude: Vec<String>,
+}
+
+impl PeripheralFilter {
+    pub(crate) fn allows(&self, id: &str, name: &str) -> bool {
+        let matches = |patterns: &[String]| {
+            patterns.iter().any(|pattern| {
+                let pattern = pattern.to_lowercase();
+                id.to_lowercase().contains(&pattern) || name.to_lowercase().contains(&pattern)
+            })
+        };
+        (self.include.is_empty() || matches(&self.include)) && !matches(&self.exclude)
+    }
+}
+
+/// Ways of combining the charge percentage of several batteries.
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Aggregate {
+    /// Weighted by each pack's full capacity, i.e. the share of the total
+    /// energy left.
+    #[default]
+    Capacity,
+    /// The plain average of the packs.
+    Mean,
+    /// The emptiest pack, for machines that stop when any pack runs out.
+    Min,
+}
+
+/// Replaces parts of a sensor's discovery config. An empty `device_class`
+/// removes the device class altogether.
+#[derive(Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SensorOverride {
+    pub(crate) device_class: Option<String>,
+    pub(crate) unit_of_measurement: Option<String>,
+    pub(crate) value_template: Option<String>,
+    pub(crate) force_update: Option<bool>,
+    /// `false` registers the entity disabled, so it only shows up in Home
+    /// Assistant once a user enables it.
+    pub(crate) enabled_by_default: Option<bool>,
+    /// When a new value of the sensor is published; on change by default.
+    #[serde(default)]
+    pub(crate) publish: PublishPolicy,
+    /// Seconds between publishes for `periodic` and `both`.
+    pub(crate) period: Option<u64>,
+}
+
+/// When a sensor's new value gets published.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PublishPolicy {
+    #[default]
+    OnChange,
+    /// Every `period`, holding changes back in between.
+    Periodic,
+    /// On change and also every `period`.
+    Both,
+}
+
+/// Names the config files in messages, e.g. "config file a.toml".
+pub(crate) fn describe(paths: &[PathBuf]) -> String {
+    let names: Vec<String> = paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    match names.as_slice() {
+        [name] => format!("config file {}", name),
+        names => format!("config files {}", names.join(", ")),
+    }
+}
+
+impl Config {
+    /// Reads `paths` in order, later files overriding earlier ones.
+    /// A file can lock keys for the files after it with e.g. `locked =
+    /// ["topic"]`, or `["*"]` for all but the intervals and sensor selection;
+    /// the system file also locks the broker settings it sets.
+    /// The locks also hold against the command line and environment, see
+    /// `check_locks`.
+    pub(crate) fn load(paths: &[PathBuf]) -> Result<Config> {
+        Config::load_layers(paths, Path::new(SYSTEM_PATH))
+    }
+
+    /// `load`, with `system` as the system config file.
+    fn load_layers(paths: &[PathBuf], system: &Path) -> Result<Config> {
+        let mut table = toml::Table::new();
+        let mut locks = Locks::default();
+        for path in paths {
+            let contents = fs::read_to_string(path)
+                .with_context(|| format!("reading config file {}", path.display()))?;
+            let file: toml::Table = toml::from_str(&contents)
+                .with_context(|| format!("parsing config file {}", path.display()))?;
+            locks.check(path, &file)?;
+            locks.add(path, &file, path == system)?;
+            merge(&mut table, file);
+        }
+        table.remove("locked");
+        let mut config =
+            Config::from_table(table, paths).with_context(|| format!("in {}", describe(paths)))?;
+        config.locks = locks;
+        Ok(config)
+    }
+
+    /// Fails if an option the files lock is `given` on the command line or in
+    /// the environment.
+    pub(crate) fn check_locks(
+        &self,
+        command: &clap::Command,
+        given: impl Fn(&str) -> bool,
+    ) -> Result<()> {
+        for arg in command.get_arguments() {
+            let Some(long) = arg.get_long() else {
+                continue;
+            };
+            let key = normalize(long);
+            if INVOCATION_KEYS.contains(&key.as_str()) && !self.locks.keys.contains_key(&key) {
+                continue;
+            }
+            if let (Some(holder), true) = (self.locks.holder(&key), given(arg.get_id().as_str())) {
+                bail!(
+                    "--{} cannot be given on the command line or in the environment, \
+                     which {} locks",
+                    long,
+                    holder.display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn from_table(table: toml::Table, paths: &[PathBuf]) -> Result<Config> {
+        let mut config: Config = toml::Value::Table(table.clone()).try_into()?;
+        for (key, sensor) in &config.sensors {
+            if sensor.publish != PublishPolicy::OnChange && sensor.period.is_none() {
+                bail!("sensors.{} publishes periodically without a period", key);
+            }
+        }
+        config.files = paths.to_vec();
+        config.table = table;
+        Ok(config)
+    }
+
+    /// The settings of the profile `name`: these overridden by its table.
+    pub(crate) fn profile(&self, name: &str) -> Result<Config> {
+        let Some(profile) = self.profiles.get(name) else {
+            bail!("no profile {:?} in {}", name, describe(&self.files));
+        };
+        if profile.contains_key("profiles") {
+            bail!(
+                "profile {:?} in {} cannot hold profiles",
+                name,
+                describe(&self.files)
+            );
+        }
+        let mut table = self.table.clone();
+        table.remove("profiles");
+        merge(&mut table, profile.clone());
+        let mut config = Config::from_table(table, &self.files)
+            .with_context(|| format!("in profile {:?} of {}", name, describe(&self.files)))?;
+        config.locks = self.locks.clone();
+        Ok(config)
+    }
+
+    /// The options of the file as arguments for `command`, leaving out those
+    /// `given` on the command line, which win over the file.
+    pub(crate) fn arguments(
+        &self,
+        command: &clap::Command,
+        given: impl Fn(&str) -> bool,
+    ) -> Result<Vec<String>> {
+        let mut arguments = Vec::new();
+        let mut options: Vec<(&str, toml::Value)> = self
+            .options
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.clone()))
+            .collect();
+        if self.peripherals.upower {
+            options.push(("peripherals", toml::Value::Boolean(true)));
+        }
+        for (key, value) in options {
+            let arg = command.get_arguments().find(|arg| {
+                arg.get_long()
+                    .is_some_and(|long| long == key.replace('_', "-") && long != "config")
+            });
+            let (Some(arg), Some(long)) = (arg, arg.and_then(|arg| arg.get_long())) else {
+                bail!("unknown option {:?}", key);
+            };
+            if given(arg.get_id().as_str()) {
+                continue;
+            }
+            let values = match value {
+                toml::Value::Array(values) => values,
+                value => vec![value],
+            };
+            for value in values {
+                match value {
+                    toml::Value::Boolean(true) => arguments.push(format!("--{}", long)),
+                    toml::Value::Boolean(false) => {}
+                    toml::Value::String(value) => arguments.push(format!("--{}={}", long, value)),
+                    toml::Value::Integer(value) => arguments.push(format!("--{}={}", long, value)),
+                    toml::Value::Float(value) => arguments.push(format!("--{}={}", long, value)),
+                    other => bail!("{} cannot be {}", key, other.type_str()),
+                }
+            }
+        }
+        Ok(arguments)
+    }
+}
+
+/// Keys locked by the config files read so far, with the file locking each.
+#[derive(Default, Clone)]
+struct Locks {
+    keys: BTreeMap<String, PathBuf>,
+    /// The file that locked all but the adjustable keys, if any did.
+    all: Option<PathBuf>,
+}
+
+/// The long name of an option as config files spell it, e.g. `fast_below`
+/// for `fast-below`.
+fn normalize(key: &str) -> String {
+    key.replace('-', "_")
+}
+
+impl Locks {
+    /// The file locking `key`, if any.
+    fn holder(&self, key: &str) -> Option<&PathBuf> {
+        let key = normalize(key);
+        self.keys.get(&key).or(self
+            .all
+            .as_ref()
+            .filter(|_| !ADJUSTABLE_KEYS.contains(&key.as_str())))
+    }
+
+    /// Fails if `file` sets a locked key, itself or in one of its profiles.
+    fn check(&self, path: &Path, file: &toml::Table) -> Result<()> {
+        let profiles = file
+            .get("profiles")
+            .and_then(toml::Value::as_table)
+            .into_iter()
+            .flat_map(|profiles| profiles.values().filter_map(toml::Value::as_table));
+        for table in std::iter::once(file).chain(profiles) {
+            for key in table.keys() {
+                if let Some(holder) = self.holder(key) {
+                    bail!(
+                        "config file {} sets {}, which {} locks",
+                        path.display(),
+                        key,
+                        holder.display()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Locks what `file` asks to, and the broker settings it sets when it is
+    /// the `system` config file.
+    fn add(&mut self, path: &Path, file: &toml::Table, system: bool) -> Result<()> {
+        let mut keys: Vec<String> = match file.get("locked") {
+            Some(locked) => locked
+                .clone()
+                .try_into::<Vec<String>>()
+                .with_context(|| format!("locked in config file {}", path.display()))?,
+            None => Vec::new(),
+        };
+        if file.contains_key("locked") {
+            keys.push(String::from("locked"));
+        }
+        if system {
+            keys.extend(
+                BROKER_KEYS
+                    .iter()
+                    .filter(|key| file.contains_key(**key))
+                    .map(|key| key.to_string()),
+            );
+        }
+        for key in keys {
+            if key == "*" {
+                self.all.get_or_insert_with(|| path.to_path_buf());
+            } else {
+                self.keys
+                    .entry(normalize(&key))
+                    .or_insert_with(|| path.to_path_buf());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `files` to a directory of their own, returning their paths in
+    /// order.
+    fn write(test: &str, files: &[&str]) -> Vec<PathBuf> {
+        let dir = env::temp_dir().join(format!("battery-daemon-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        files
+            .iter()
+            .enumerate()
+            .map(|(i, contents)| {
+                let path = dir.join(format!("{}.toml", i));
+                fs::write(&path, contents).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    fn option(config: &Config, key: &str) -> Option<toml::Value> {
+        config.options.get(key).cloned()
+    }
+
+    #[test]
+    fn later_files_override_earlier_ones() {
+        let paths = write(
+            "override",
+            &[
+                "interval = 60\ntopic = \"a\"\n[sensors.percentage]\ndevice_class = \"x\"",
+                "interval = 30\n[sensors.percentage]\nperiod = 5\npublish = \"both\"",
+            ],
+        );
+        let config = Config::load_layers(&paths, Path::new("/nonexistent")).unwrap();
+        assert_eq!(option(&config, "interval"), Some(toml::Value::Integer(30)));
+        assert_eq!(option(&config, "topic"), Some(toml::Value::from("a")));
+        // Tables merge key by key.
+        let sensor = &config.sensors["percentage"];
+        assert_eq!(sensor.device_class.as_deref(), Some("x"));
+        assert_eq!(sensor.period, Some(5));
+    }
+
+    #[test]
+    fn system_file_locks_the_broker_it_sets() {
+        let paths = write("broker", &["hostname = \"broker\"", "hostname = \"other\""]);
+        let err = Config::load_layers(&paths, &paths[0]).err().unwrap();
+        assert!(err.to_string().contains("sets hostname"));
+        // Only the system file locks them implicitly.
+        assert!(Config::load_layers(&paths, Path::new("/nonexistent")).is_ok());
+    }
+
+    #[test]
+    fn locked_keys_hold_against_later_files_and_their_profiles() {
+        let paths = write(
+            "locked",
+            &["locked = [\"topic\"]", "[profiles.a]\ntopic = \"b\""],
+        );
+        assert!(Config::load_layers(&paths, Path::new("/nonexistent")).is_err());
+        let paths = write("relock", &["locked = [\"topic\"]", "locked = []"]);
+        assert!(Config::load_layers(&paths, Path::new("/nonexistent")).is_err());
+    }
+
+    #[test]
+    fn locking_everything_leaves_the_adjustable_keys() {
+        let paths = write(
+            "all",
+            &[
+                "locked = [\"*\"]",
+                "interval = 30\nexclude-sensor = [\"temperature\"]",
+            ],
+        );
+        let config = Config::load_layers(&paths, Path::new("/nonexistent")).unwrap();
+        assert!(!config.options.contains_key("locked"));
+        let paths = write("all-topic", &["locked = [\"*\"]", "topic = \"b\""]);
+        assert!(Config::load_layers(&paths, Path::new("/nonexistent")).is_err());
+    }
+
+    #[test]
+    fn locks_hold_against_the_command_line() {
+        let paths = write("cli", &["locked = [\"*\", \"dry-run\"]"]);
+        let config = Config::load_layers(&paths, Path::new("/nonexistent")).unwrap();
+        let command = clap::Command::new("test")
+            .arg(clap::Arg::new("hostname").long("hostname"))
+            .arg(clap::Arg::new("interval").long("interval"))
+            .arg(clap::Arg::new("verbose").long("verbose"))
+            .arg(clap::Arg::new("dry_run").long("dry-run"));
+        assert!(config.check_locks(&command, |id| id == "hostname").is_err());
+        assert!(config.check_locks(&command, |id| id == "interval").is_ok());
+        assert!(config.check_locks(&command, |id| id == "verbose").is_ok());
+        assert!(config.check_locks(&command, |id| id == "dry_run").is_err());
+        assert!(config.check_locks(&command, |_| false).is_ok());
+    }
+
+    #[test]
+    fn profiles_keep_the_locks() {
+        let paths = write(
+            "profiles",
+            &["locked = [\"port\"]\n[profiles.a]\ntopic = \"b\""],
+        );
+        let config = Config::load_layers(&paths, Path::new("/nonexistent")).unwrap();
+        let profile = config.profile("a").unwrap();
+        let command = clap::Command::new("test").arg(clap::Arg::new("port").long("port"));
+        assert!(profile.check_locks(&command, |id| id == "port").is_err());
+    }
+}
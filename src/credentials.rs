@@ -0,0 +1,68 @@
+use crate::Args;
+use anyhow::{bail, Context, Result};
+use std::{env, fs, path::PathBuf};
+use zeroize::Zeroizing;
+
+/// Name of the systemd credential holding the broker password, e.g.
+/// `LoadCredential=mqtt-password:/etc/battery-monitor-daemon/password`.
+const CREDENTIAL: &str = "mqtt-password";
+
+/// Service the broker passwords are stored under in the OS keyring.
+const KEYRING_SERVICE: &str = "battery-monitor-daemon";
+
+/// Looks the password up in the OS keyring under `user@host`.
+fn keyring_password(username: &str, hostname: &str) -> Result<Zeroizing<String>> {
+    let account = format!("{}@{}", username, hostname);
+    // The Secret Service client blocks on D-Bus, which must not stall the
+    // runtime.
+    let password = tokio::task::block_in_place(|| {
+        keyring::Entry::new(KEYRING_SERVICE, &account).and_then(|entry| entry.get_password())
+    })
+    .with_context(|| {
+        format!(
+            "reading the password of {} from the keyring, service {}",
+            account, KEYRING_SERVICE
+        )
+    })?;
+    Ok(Zeroizing::new(password))
+}
+
+/// The password file: `--password-file`, or the systemd credential when the
+/// service was started with one.
+fn password_path(args: &Args) -> Option<PathBuf> {
+    args.password_file.clone().or_else(|| {
+        let path = PathBuf::from(env::var_os("CREDENTIALS_DIRECTORY")?).join(CREDENTIAL);
+        path.is_file().then_some(path)
+    })
+}
+
+/// Reads the broker password from the keyring with `--keyring`, else from
+/// the password file without its trailing newline. The buffers
+/// holding it are wiped when dropped; rumqttc keeps its own copy for
+/// reconnecting.
+pub(crate) fn password(args: &Args) -> Result<Option<Zeroizing<String>>> {
+    if args.keyring {
+        let Some(username) = &args.username else {
+            bail!("--keyring needs --username to find the password");
+        };
+        return keyring_password(username, &args.hostname).map(Some);
+    }
+    let Some(path) = password_path(args) else {
+        return Ok(None);
+    };
+    let contents = Zeroizing::new(
+        fs::read_to_string(&path)
+            .with_context(|| format!("reading password file {}", path.display()))?,
+    );
+    let password = Zeroizing::new(String::from(contents.trim_end_matches(['\r', '\n'])));
+    if password.is_empty() {
+        bail!("password file {} is empty", path.display());
+    }
+    if args.username.is_none() {
+        bail!(
+            "password file {} needs --username, the broker ignores a password alone",
+            path.display()
+        );
+    }
+    Ok(Some(password))
+}
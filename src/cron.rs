@@ -0,0 +1,202 @@
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDateTime, TimeZone, Timelike};
+
+/// How far ahead `next` looks before giving up on an expression that never
+/// matches, like the 31st of February.
+const HORIZON_DAYS: i64 = 366 * 4;
+
+/// A standard five-field cron expression, `MINUTE HOUR DAY MONTH WEEKDAY`,
+/// evaluated in local time like cron does.
+#[derive(Clone)]
+pub(crate) struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// Parses one field into a bit set of the values in `min..=max` it matches:
+/// `*`, `N`, `N-M` and a `/STEP` after any of those, separated by commas.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("invalid step {:?}", step)),
+            },
+            None => (part, 1),
+        };
+        let value = |s: &str| match s.parse::<u32>() {
+            Ok(v) if (min..=max).contains(&v) => Ok(v),
+            _ => Err(format!("{:?} is not between {} and {}", s, min, max)),
+        };
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((from, to)) => (value(from)?, value(to)?),
+            // `N/STEP` runs from N to the end, like in Vixie cron.
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if from > to {
+            return Err(format!("range {:?} runs backwards", range));
+        }
+        for v in (from..=to).step_by(step as usize) {
+            bits |= 1 << v;
+        }
+    }
+    Ok(bits)
+}
+
+pub(crate) fn parse_schedule(s: &str) -> Result<Schedule, String> {
+    let fields: Vec<&str> = s.split_whitespace().collect();
+    let [minutes, hours, days, months, weekdays] = fields[..] else {
+        return Err(format!(
+            "expected MINUTE HOUR DAY MONTH WEEKDAY, got {} fields",
+            fields.len()
+        ));
+    };
+    let mut weekday_bits = parse_field(weekdays, 0, 7)?;
+    // Both 0 and 7 are Sunday.
+    if weekday_bits & (1 << 7) != 0 {
+        weekday_bits |= 1;
+    }
+    Ok(Schedule {
+        minutes: parse_field(minutes, 0, 59)?,
+        hours: parse_field(hours, 0, 23)?,
+        days: parse_field(days, 1, 31)?,
+        months: parse_field(months, 1, 12)?,
+        weekdays: weekday_bits,
+        any_day: days.starts_with('*'),
+        any_weekday: weekdays.starts_with('*'),
+    })
+}
+
+impl Schedule {
+    /// Whether the schedule fires on the day of `t`. Like cron, a day
+    /// matches either the day of the month or the weekday when both are
+    /// restricted.
+    fn matches_day(&self, t: &NaiveDateTime) -> bool {
+        let day = self.days & (1 << t.day()) != 0;
+        let weekday = self.weekdays & (1 << t.weekday().num_days_from_sunday()) != 0;
+        let date = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        date && self.months & (1 << t.month()) != 0
+    }
+
+    /// The first time after `after` the schedule fires at, on a whole minute.
+    pub(crate) fn next(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = after.naive_local().with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let end = start + Duration::days(HORIZON_DAYS);
+        let mut t = start;
+        while t < end {
+            if !self.matches_day(&t) {
+                t = t.date().succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if self.hours & (1 << t.hour()) == 0 {
+                t = t.with_minute(0)? + Duration::hours(1);
+            } else if self.minutes & (1 << t.minute()) == 0 {
+                t += Duration::minutes(1);
+            } else if let Some(at) = Local.from_local_datetime(&t).earliest() {
+                return Some(at);
+            } else {
+                // Skipped by a daylight saving time change.
+                t += Duration::minutes(1);
+            }
+        }
+        None
+    }
+
+    /// The longest wait between two runs over the coming `days`, to compare
+    /// with the heartbeat.
+    pub(crate) fn longest_gap(&self, days: i64) -> Option<std::time::Duration> {
+        let now = Local::now();
+        let mut prev = self.next(now)?;
+        let mut longest = Duration::zero();
+        while prev < now + Duration::days(days) {
+            let next = self.next(prev)?;
+            longest = longest.max(next - prev);
+            prev = next;
+        }
+        longest.to_std().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Local> {
+        let t = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap();
+        Local.from_local_datetime(&t).earliest().unwrap()
+    }
+
+    fn next(expr: &str, after: &str) -> String {
+        let schedule = parse_schedule(expr).unwrap();
+        schedule
+            .next(at(after))
+            .unwrap()
+            .format("%Y-%m-%d %H:%M")
+            .to_string()
+    }
+
+    #[test]
+    fn fields_accept_ranges_steps_and_lists() {
+        assert_eq!(parse_field("*", 0, 3), Ok(0b1111));
+        assert_eq!(parse_field("2", 0, 59), Ok(1 << 2));
+        assert_eq!(parse_field("1-3", 0, 59), Ok(0b1110));
+        assert_eq!(parse_field("*/20", 0, 59), Ok(1 | 1 << 20 | 1 << 40));
+        assert_eq!(parse_field("50/5", 0, 59), Ok(1 << 50 | 1 << 55));
+        assert_eq!(parse_field("1,4-5", 0, 59), Ok(0b110010));
+    }
+
+    #[test]
+    fn invalid_fields_and_expressions_are_rejected() {
+        assert!(parse_field("60", 0, 59).is_err());
+        assert!(parse_field("5-1", 0, 59).is_err());
+        assert!(parse_field("*/0", 0, 59).is_err());
+        assert!(parse_field("a", 0, 59).is_err());
+        assert!(parse_schedule("* * * *").is_err());
+        assert!(parse_schedule("* * * * * *").is_err());
+        assert!(parse_schedule("* * 0 * *").is_err());
+    }
+
+    #[test]
+    fn next_fires_on_the_following_matching_minute() {
+        assert_eq!(next("* * * * *", "2024-05-01 12:00"), "2024-05-01 12:01");
+        assert_eq!(next("*/15 * * * *", "2024-05-01 12:07"), "2024-05-01 12:15");
+        assert_eq!(next("30 2 * * *", "2024-05-01 12:00"), "2024-05-02 02:30");
+        assert_eq!(next("0 0 1 * *", "2024-05-01 00:00"), "2024-06-01 00:00");
+        assert_eq!(next("0 0 29 2 *", "2024-03-01 00:00"), "2028-02-29 00:00");
+    }
+
+    #[test]
+    fn weekdays_count_sunday_twice_and_or_with_days() {
+        // 2024-05-01 is a Wednesday; 7 and 0 are both Sunday.
+        assert_eq!(next("0 9 * * 1-5", "2024-05-03 10:00"), "2024-05-06 09:00");
+        assert_eq!(next("0 9 * * 7", "2024-05-01 10:00"), "2024-05-05 09:00");
+        assert_eq!(next("0 9 * * 0", "2024-05-01 10:00"), "2024-05-05 09:00");
+        // With both restricted, either the day or the weekday matches.
+        assert_eq!(next("0 9 15 * 5", "2024-05-01 10:00"), "2024-05-03 09:00");
+        assert_eq!(next("0 9 2 * 5", "2024-05-01 10:00"), "2024-05-02 09:00");
+    }
+
+    #[test]
+    fn impossible_dates_never_fire() {
+        let schedule = parse_schedule("0 0 31 2 *").unwrap();
+        assert!(schedule.next(at("2024-01-01 00:00")).is_none());
+        assert!(schedule.longest_gap(7).is_none());
+    }
+
+    #[test]
+    fn longest_gap_is_the_step() {
+        let schedule = parse_schedule("*/10 * * * *").unwrap();
+        assert_eq!(
+            schedule.longest_gap(1),
+            Some(std::time::Duration::from_secs(600))
+        );
+    }
+}
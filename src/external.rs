@@ -0,0 +1,45 @@
+use crate::{
+    config::ExternalSource,
+    peripherals::{self, Peripheral, PeripheralState},
+};
+use anyhow::{bail, Context, Result};
+use std::time::Duration;
+use tokio::{process::Command, time};
+
+/// How long a command may run before its reading is skipped.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs the source's command and reads the charge from its JSON output, e.g.
+/// `termux-battery-status` on a phone reached over SSH.
+pub(crate) async fn read(source: &ExternalSource) -> Result<Peripheral> {
+    let Some((program, args)) = source.command.split_first() else {
+        bail!("external source {} has an empty command", source.name);
+    };
+    // Killed when the timeout drops it, so a hung command does not linger.
+    let command = Command::new(program).args(args).kill_on_drop(true).output();
+    let output = time::timeout(TIMEOUT, command)
+        .await
+        .with_context(|| format!("{} did not finish within {:?}", program, TIMEOUT))?
+        .with_context(|| format!("running {}", program))?;
+    if !output.status.success() {
+        bail!("{} exited with {}", program, output.status);
+    }
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("parsing the output of {}", program))?;
+    let percentage = json
+        .get(&source.percentage_key)
+        .and_then(|value| value.as_f64())
+        .with_context(|| format!("{} printed no {:?} number", program, source.percentage_key))?;
+    let status = json
+        .get(&source.status_key)
+        .and_then(|value| value.as_str())
+        .map(|status| status.to_lowercase());
+    Ok(Peripheral {
+        id: peripherals::id("external", &source.name),
+        name: source.name.clone(),
+        state: PeripheralState {
+            percentage: percentage as f32,
+            status,
+        },
+    })
+}
@@ -0,0 +1,206 @@
+use crate::{config, sysfs, Args};
+use anyhow::{bail, Context, Result};
+use clap::{Arg, ArgAction, CommandFactory};
+use gethostname::gethostname;
+use std::{fs, path::Path};
+
+/// Options left out of the file: `config` cannot point elsewhere from inside
+/// the file, `peripherals` is the `upower` key of the `[peripherals]` table
+/// there, and help and version are not settings.
+const SKIPPED: &[&str] = &["config", "help", "peripherals", "version"];
+
+/// Value names of the numeric options, whose placeholders go unquoted.
+const NUMERIC: &[&str] = &["DECIMALS", "HOURS", "PERCENT", "SECONDS"];
+
+/// Width the descriptions are wrapped to, including the leading `# `.
+const WIDTH: usize = 78;
+
+/// Wraps `text` into `# ` comment lines.
+fn comment(text: &str) -> String {
+    let mut lines = Vec::new();
+    let mut line = String::from("#");
+    for word in text.split_whitespace() {
+        if line.len() + 1 + word.len() > WIDTH && line != "#" {
+            lines.push(line);
+            line = String::from("#");
+        }
+        line.push(' ');
+        line.push_str(word);
+    }
+    lines.push(line);
+    lines.join("\n") + "\n"
+}
+
+/// A value as TOML: numbers and booleans bare, anything else quoted.
+fn toml_value(value: &str) -> String {
+    if value.parse::<i64>().is_ok() || value.parse::<f64>().is_ok() || value == "true" {
+        String::from(value)
+    } else {
+        toml::Value::String(String::from(value)).to_string()
+    }
+}
+
+/// The commented-out line of one option: its default, or what was detected
+/// on this machine, or a placeholder naming the expected value.
+fn example(arg: &Arg, key: &str, detected: Option<String>) -> String {
+    if let Some(detected) = detected {
+        return format!("# {} = {}\n", key, detected);
+    }
+    if matches!(arg.get_action(), ArgAction::SetTrue | ArgAction::Count) {
+        return format!("# {} = true\n", key);
+    }
+    let defaults: Vec<String> = arg
+        .get_default_values()
+        .iter()
+        .map(|value| toml_value(&value.to_string_lossy()))
+        .collect();
+    let placeholder = || {
+        let name = arg
+            .get_value_names()
+            .and_then(|names| names.first())
+            .map(|name| name.to_string())
+            .unwrap_or_else(|| key.to_uppercase());
+        if NUMERIC.contains(&name.as_str()) {
+            format!("<{}>", name)
+        } else {
+            format!("\"<{}>\"", name)
+        }
+    };
+    match (arg.get_action(), defaults.as_slice()) {
+        (ArgAction::Append, _) => format!("# {} = [{}]\n", key, placeholder()),
+        (_, [default]) => format!("# {} = {}\n", key, default),
+        _ => format!("# {} = {}\n", key, placeholder()),
+    }
+}
+
+/// Values detected on this machine for the options they make sense for.
+fn detected(key: &str) -> Option<String> {
+    match key {
+        "name" => gethostname()
+            .into_string()
+            .ok()
+            .map(|hostname| toml_value(&hostname)),
+        "battery" => {
+            let names: Vec<String> = sysfs::supplies("Battery")
+                .iter()
+                .filter_map(|dir| dir.file_name()?.to_str().map(toml_value))
+                .collect();
+            (!names.is_empty()).then(|| format!("[{}]", names.join(", ")))
+        }
+        _ => None,
+    }
+}
+
+/// A config file with every option commented out at its default, plus
+/// examples of the tables only the file can hold.
+pub(crate) fn template() -> String {
+    let mut template = comment(
+        "Configuration of battery-monitor-daemon. Every option of the command \
+         line can be set here by its long name; uncomment a line to change it. \
+         The command line and BATTERY_DAEMON_* environment variables win over \
+         this file, except for the broker options \
+         /etc/battery-monitor-daemon/config.toml sets and the options its \
+         `locked` lists, which nothing else can override.",
+    );
+    let command = Args::command();
+    for arg in command.get_arguments() {
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+        if SKIPPED.contains(&long) || arg.is_hide_set() {
+            continue;
+        }
+        let key = long.replace('-', "_");
+        template.push('\n');
+        if let Some(help) = arg.get_long_help().or(arg.get_help()) {
+            template.push_str(&comment(&help.to_string()));
+        }
+        let values: Vec<String> = arg
+            .get_possible_values()
+            .iter()
+            .filter(|value| !value.is_hide_set())
+            .map(|value| value.get_name().to_string())
+            .collect();
+        if !values.is_empty() && !matches!(arg.get_action(), ArgAction::SetTrue) {
+            template.push_str(&comment(&format!("One of: {}.", values.join(", "))));
+        }
+        template.push_str(&example(arg, &key, detected(long)));
+    }
+    template.push_str(
+        "
+# How the charge of several batteries is combined: capacity, mean or min.
+# aggregate = \"capacity\"
+
+# Discovery overrides per sensor, or per battery with e.g. percentage_bat1.
+# [sensors.energy_rate]
+# device_class = \"power\"
+# unit_of_measurement = \"W\"
+# force_update = true
+# enabled_by_default = false
+# publish = \"periodic\"  # on_change, periodic or both
+# period = 600
+
+# Which peripherals are published, matching parts of their name or id.
+# [peripherals]
+# upower = true
+# include = [\"mouse\"]
+# exclude = [\"headset\"]
+
+# Outputs published to side by side, each overriding the settings above.
+# [profiles.home_assistant]
+# [profiles.metrics]
+# hostname = \"metrics.example.com\"
+# username = \"laptop\"
+# password_file = \"/etc/battery-monitor-daemon/metrics-password\"
+# topic = \"metrics/laptop/battery\"
+# no_discovery = true
+
+# Batteries read from the JSON a command prints, e.g. of a phone.
+# [[external]]
+# name = \"Phone\"
+# command = [\"ssh\", \"phone\", \"termux-battery-status\"]
+# interval = 300
+# percentage_key = \"percentage\"
+# status_key = \"status\"
+",
+    );
+    template
+}
+
+/// Writes the template to `path`, refusing to replace an existing file
+/// unless `force`.
+fn write(path: &Path, force: bool) -> Result<()> {
+    if path.exists() && !force {
+        bail!(
+            "{} already exists; pass --force to replace it",
+            path.display()
+        );
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    }
+    fs::write(path, template()).with_context(|| format!("writing {}", path.display()))
+}
+
+/// Prints the template, or writes it to the user's config path, returning
+/// whether that worked.
+pub(crate) fn run(stdout: bool, force: bool) -> bool {
+    if stdout {
+        print!("{}", template());
+        return true;
+    }
+    let Some(path) = config::user_path() else {
+        eprintln!("neither XDG_CONFIG_HOME nor HOME is set; pass --stdout instead");
+        return false;
+    };
+    match write(&path, force) {
+        Ok(()) => {
+            println!("wrote {}, which is read on every start", path.display());
+            true
+        }
+        Err(e) => {
+            eprintln!("{:#}", e);
+            false
+        }
+    }
+}
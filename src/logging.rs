@@ -0,0 +1,107 @@
+use clap::ValueEnum;
+use rumqttc::ConnectionError;
+use std::io;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::EnvFilter;
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Default)]
+pub(crate) enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line with the level, target, message and fields,
+    /// for log shippers
+    Json,
+}
+
+/// A level for `--log-level`, the same as counting `-v` or `-q` up from info.
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+pub(crate) enum LogLevel {
+    /// Nothing, like -qqq
+    Off,
+    /// Only errors, like -qq
+    Error,
+    /// Only problems, like -q
+    Warn,
+    Info,
+    /// What gets published, like -v
+    Debug,
+    /// Everything, like -vv
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> LevelFilter {
+        match level {
+            LogLevel::Off => LevelFilter::OFF,
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+            LogLevel::Trace => LevelFilter::TRACE,
+        }
+    }
+}
+
+/// The level `-v` and `-q` pick, starting from `--log-level` or info: `-v`
+/// shows what gets published, `-vv` everything, `-q` only problems and `-qq`
+/// only errors.
+fn level(verbose: u8, quiet: u8, log_level: Option<LogLevel>) -> LevelFilter {
+    if verbose == 0 && quiet == 0 {
+        if let Some(log_level) = log_level {
+            return log_level.into();
+        }
+    }
+    match i16::from(verbose) - i16::from(quiet) {
+        i16::MIN..=-3 => LevelFilter::OFF,
+        -2 => LevelFilter::ERROR,
+        -1 => LevelFilter::WARN,
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+/// Logs to stderr, leaving stdout to the reports of the subcommands and
+/// `--dry-run`. `RUST_LOG`, e.g. `rumqttc=debug,info`, wins over `-v`, `-q`
+/// and `--log-level`.
+pub(crate) fn init(verbose: u8, quiet: u8, log_level: Option<LogLevel>, format: LogFormat) {
+    let filter = EnvFilter::builder()
+        .with_default_directive(level(verbose, quiet, log_level).into())
+        .from_env_lossy();
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr);
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().flatten_event(true).init(),
+    }
+}
+
+/// A short name for what went wrong with the broker connection, e.g.
+/// `io:ConnectionRefused` or `timeout`, to filter logs by.
+pub(crate) fn error_kind(e: &ConnectionError) -> String {
+    match e {
+        ConnectionError::Io(e) => format!("io:{:?}", e.kind()),
+        ConnectionError::MqttState(_) => String::from("state"),
+        ConnectionError::Timeout(_) => String::from("timeout"),
+        ConnectionError::ConnectionRefused(code) => format!("refused:{:?}", code),
+        ConnectionError::NotConnAck(_) => String::from("no_connack"),
+        ConnectionError::RequestsDone => String::from("requests_done"),
+        _ => String::from("tls"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_win_over_the_log_level() {
+        assert_eq!(level(0, 0, None), LevelFilter::INFO);
+        assert_eq!(level(0, 0, Some(LogLevel::Warn)), LevelFilter::WARN);
+        assert_eq!(level(2, 0, Some(LogLevel::Error)), LevelFilter::TRACE);
+        assert_eq!(level(0, 1, Some(LogLevel::Trace)), LevelFilter::WARN);
+        assert_eq!(level(0, 3, None), LevelFilter::OFF);
+    }
+}
@@ -0,0 +1,77 @@
+use crate::ChargeInfo;
+use core_foundation::{
+    base::{kCFAllocatorDefault, CFType, TCFType},
+    number::CFNumber,
+    string::CFString,
+};
+use io_kit_sys::{
+    kIOMasterPortDefault, types::io_registry_entry_t, IOObjectRelease,
+    IORegistryEntryCreateCFProperty, IOServiceGetMatchingService, IOServiceMatching,
+};
+
+/// The battery's IOKit registry entry, released on drop.
+struct SmartBattery(io_registry_entry_t);
+
+impl SmartBattery {
+    fn find() -> Option<SmartBattery> {
+        // SAFETY: IOServiceGetMatchingService consumes the matching
+        // dictionary, and returns 0 when no battery matches.
+        let entry = unsafe {
+            IOServiceGetMatchingService(
+                kIOMasterPortDefault,
+                IOServiceMatching(c"AppleSmartBattery".as_ptr()),
+            )
+        };
+        (entry != 0).then_some(SmartBattery(entry))
+    }
+
+    fn property(&self, key: &str) -> Option<CFType> {
+        let key = CFString::new(key);
+        // SAFETY: the returned reference is owned by us (create rule) or null.
+        let value = unsafe {
+            IORegistryEntryCreateCFProperty(
+                self.0,
+                key.as_concrete_TypeRef(),
+                kCFAllocatorDefault,
+                0,
+            )
+        };
+        (!value.is_null()).then(|| unsafe { CFType::wrap_under_create_rule(value) })
+    }
+
+    fn number(&self, key: &str) -> Option<i64> {
+        self.property(key)?.downcast::<CFNumber>()?.to_i64()
+    }
+}
+
+impl Drop for SmartBattery {
+    fn drop(&mut self) {
+        // SAFETY: the entry was returned to us by IOServiceGetMatchingService.
+        unsafe { IOObjectRelease(self.0) };
+    }
+}
+
+/// Adds what only IOKit knows to a reading from the battery crate: the
+/// signed instantaneous current, the cycle count if it is missing, and the
+/// condition macOS would show.
+pub(crate) fn augment(info: &mut ChargeInfo) {
+    let Some(battery) = SmartBattery::find() else {
+        return;
+    };
+    // Milliamperes, negative while discharging.
+    if let Some(amperage) = battery.number("InstantAmperage") {
+        info.current = Some(amperage as f32 / 1000.0);
+    }
+    if info.cycle_count.is_none() {
+        info.cycle_count = battery
+            .number("CycleCount")
+            .and_then(|cycles| u32::try_from(cycles).ok());
+    }
+    info.condition = battery.number("PermanentFailureStatus").map(|failure| {
+        String::from(if failure == 0 {
+            "Normal"
+        } else {
+            "Service Recommended"
+        })
+    });
+}
@@ -1,34 +1,1214 @@
-use anyhow::Result;
-use battery::{units::ratio::percent, State};
-use clap::Parser;
+use anyhow::{bail, Context, Result};
+use battery::{
+    units::{
+        electric_potential::volt, energy::watt_hour, power::watt, ratio::percent,
+        thermodynamic_temperature::degree_celsius,
+    },
+    State,
+};
+use chrono::{DateTime, Local, Utc};
+use clap::{
+    parser::ValueSource, ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand,
+    ValueEnum,
+};
 use core::fmt;
 use gethostname::gethostname;
-use rumqttc::{AsyncClient, MqttOptions, QoS};
-use serde::Serialize;
-use std::{mem, time::Duration};
-use tokio::{sync::mpsc, task, time};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    env,
+    ffi::OsString,
+    fs, future, io, mem,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::{
+    sync::{mpsc, Notify},
+    task, time,
+};
+use tracing::{debug, error, info, warn, Instrument};
+use zeroize::Zeroizing;
 
-#[derive(Parser)]
+use config::{Aggregate, Config, ExternalSource, PublishPolicy, SensorOverride};
+use logging::{LogFormat, LogLevel};
+use peripherals::{Peripheral, PeripheralState};
+use sensors::SensorFilter;
+use source::{Backend, ReadOptions, Reading};
+use units::{DurationUnit, PercentUnit, PowerUnit, Timestamp, TimestampFormat};
+
+mod abbreviate;
+#[cfg(target_os = "linux")]
+mod acpid;
+#[cfg(target_os = "linux")]
+mod bluez;
+mod check;
+mod config;
+mod credentials;
+mod cron;
+mod external;
+mod init_config;
+mod logging;
+#[cfg(target_os = "macos")]
+mod macos;
+mod nut;
+mod once;
+mod peripherals;
+#[cfg(target_os = "linux")]
+mod platform;
+mod release;
+mod remove;
+mod sensors;
+mod source;
+mod sysfs;
+mod thermal;
+mod units;
+#[cfg(target_os = "linux")]
+mod upower;
+mod verify;
+#[cfg(windows)]
+mod windows;
+
+/// How often the firmware's critical battery flags are checked, independent
+/// of the poll interval.
+const ALARM_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often `--release-url` is checked for a newer daemon.
+const RELEASE_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 3600);
+
+#[derive(Parser, Clone)]
 #[command(author, version, about, long_about=None)]
 struct Args {
-    #[arg(short, long, default_value = "battery-daemon/status/battery")]
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// TOML file with any of these options by their long name plus per-sensor
+    /// discovery overrides, read instead of
+    /// $XDG_CONFIG_HOME/battery-monitor-daemon/config.toml and on top of
+    /// /etc/battery-monitor-daemon/config.toml, which is always read. Every
+    /// option can also be set as an environment variable, e.g.
+    /// BATTERY_DAEMON_DISCOVERY_TOPIC; the command line wins over the
+    /// environment, which wins over the files. The broker options /etc sets
+    /// and the options its `locked` lists cannot be overridden by this file,
+    /// the environment or the command line
+    #[arg(short, long, env = "BATTERY_DAEMON_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Only use this `[profiles.NAME]` of the config files; every profile is
+    /// published to side by side otherwise
+    #[arg(long, env = "BATTERY_DAEMON_PROFILE", value_name = "NAME")]
+    profile: Option<String>,
+
+    #[arg(skip)]
+    settings: Config,
+
+    #[arg(
+        short,
+        long,
+        env = "BATTERY_DAEMON_TOPIC",
+        default_value = "battery-daemon/status/battery"
+    )]
     topic: String,
 
-    #[arg(long, default_value = "localhost")]
+    #[arg(long, env = "BATTERY_DAEMON_HOSTNAME", default_value = "localhost")]
     hostname: String,
 
-    #[arg(short, long, default_value_t = 1883)]
+    #[arg(short, long, env = "BATTERY_DAEMON_PORT", default_value_t = 1883)]
     port: u16,
 
-    #[arg(long, default_value = "homeassistant")]
+    /// User name to log in to the broker with
+    #[arg(long, env = "BATTERY_DAEMON_USERNAME")]
+    username: Option<String>,
+
+    /// File holding the broker password, so it stays out of process listings
+    /// and unit files; defaults to the systemd credential `mqtt-password`
+    #[arg(long, env = "BATTERY_DAEMON_PASSWORD_FILE", value_name = "PATH")]
+    password_file: Option<PathBuf>,
+
+    /// Read the broker password from the OS keyring (Secret Service, macOS
+    /// Keychain or Windows Credential Manager), stored for service
+    /// battery-monitor-daemon and account USERNAME@HOSTNAME, e.g. with
+    /// `secret-tool store --label=mqtt service battery-monitor-daemon
+    /// username me@broker`
+    #[arg(
+        long,
+        env = "BATTERY_DAEMON_KEYRING",
+        requires = "username",
+        conflicts_with = "password_file"
+    )]
+    keyring: bool,
+
+    #[arg(skip)]
+    password: Option<Zeroizing<String>>,
+
+    #[arg(
+        long,
+        env = "BATTERY_DAEMON_DISCOVERY_TOPIC",
+        default_value = "homeassistant"
+    )]
     discovery_topic: String,
+
+    /// Identity used instead of the hostname, for machines with generated
+    /// hostnames like `nixos-7f3a2`; becomes the device name and, sanitized,
+    /// the object id in discovery topics and entity ids
+    #[arg(long, env = "BATTERY_DAEMON_DEVICE_NAME", value_name = "NAME", value_parser = parse_device_name)]
+    device_name: Option<String>,
+
+    /// Friendly device name shown in Home Assistant, e.g. "Ethan's Laptop";
+    /// defaults to the device name or hostname
+    #[arg(long, env = "BATTERY_DAEMON_NAME")]
+    name: Option<String>,
+
+    /// Object id used in discovery topics and entity ids instead of the
+    /// sanitized hostname
+    #[arg(long, env = "BATTERY_DAEMON_OBJECT_ID", value_parser = parse_object_id)]
+    object_id: Option<String>,
+
+    /// Derive the object id from the machine-id and the battery's serial
+    /// number instead of the hostname, and key per-battery topics by serial,
+    /// so renaming the host or moving a pack keeps its entities. Run
+    /// `remove-discovery` first when switching an existing host over
+    #[arg(long, env = "BATTERY_DAEMON_STABLE_ID", conflicts_with = "object_id")]
+    stable_id: bool,
+
+    /// Optional node_id inserted into discovery topics
+    #[arg(long, env = "BATTERY_DAEMON_NODE_ID", value_parser = parse_object_id)]
+    node_id: Option<String>,
+
+    /// Fleet this host belongs to, e.g. "office"; used as the node_id and to
+    /// prefix the object ids and device name so many hosts sort predictably
+    #[arg(long, env = "BATTERY_DAEMON_FLEET", value_parser = parse_object_id)]
+    fleet: Option<String>,
+
+    /// Only publish the state JSON, without any Home Assistant discovery configs
+    #[arg(long, env = "BATTERY_DAEMON_NO_DISCOVERY", conflicts_with_all = ["device_discovery", "rediscover_interval"])]
+    no_discovery: bool,
+
+    /// Publish one device-based discovery config holding every entity instead
+    /// of one config per entity (Home Assistant 2024.11+). Run
+    /// `remove-discovery` first when switching an existing host over.
+    #[arg(long, env = "BATTERY_DAEMON_DEVICE_DISCOVERY")]
+    device_discovery: bool,
+
+    /// Use Home Assistant's abbreviated keys (`stat_t`, `uniq_id`, ...) to
+    /// shrink the retained discovery configs
+    #[arg(long, env = "BATTERY_DAEMON_ABBREVIATE")]
+    abbreviate: bool,
+
+    /// Have Home Assistant record every state message, even when the value
+    /// repeats; can also be set per sensor in the config file
+    #[arg(long, env = "BATTERY_DAEMON_FORCE_UPDATE")]
+    force_update: bool,
+
+    /// Icon for one sensor, e.g. `percentage=mdi:laptop`; repeatable
+    #[arg(long = "icon", env = "BATTERY_DAEMON_ICON", value_delimiter = ',', value_name = "SENSOR=ICON", value_parser = parse_key_val)]
+    icons: Vec<(String, String)>,
+
+    /// Sensor to announce and publish, e.g. `percentage`; repeatable. When
+    /// given, every other sensor and payload value stays on the machine
+    #[arg(
+        long = "include-sensor",
+        env = "BATTERY_DAEMON_INCLUDE_SENSOR",
+        value_delimiter = ',',
+        value_name = "SENSOR"
+    )]
+    include_sensors: Vec<String>,
+
+    /// Sensor to neither announce nor publish, e.g. `temperature`; repeatable
+    #[arg(
+        long = "exclude-sensor",
+        env = "BATTERY_DAEMON_EXCLUDE_SENSOR",
+        value_delimiter = ',',
+        value_name = "SENSOR"
+    )]
+    exclude_sensors: Vec<String>,
+
+    /// Re-publish the discovery configs every this many hours, in addition to
+    /// after every (re)connect
+    #[arg(long, env = "BATTERY_DAEMON_REDISCOVER_INTERVAL", value_name = "HOURS", value_parser = clap::value_parser!(u64).range(1..))]
+    rediscover_interval: Option<u64>,
+
+    /// Kernel name of a battery to read, e.g. `BAT1`; repeatable. All
+    /// batteries are read by default
+    #[arg(
+        long = "battery",
+        env = "BATTERY_DAEMON_BATTERY",
+        value_delimiter = ',',
+        value_name = "NAME"
+    )]
+    batteries: Vec<String>,
+
+    /// Unit the charge and other percentages of the battery are published in
+    #[arg(long, env = "BATTERY_DAEMON_PERCENT_UNIT", value_enum, default_value_t = PercentUnit::default())]
+    percent_unit: PercentUnit,
+
+    /// Unit the power of the battery is published in
+    #[arg(long, env = "BATTERY_DAEMON_POWER_UNIT", value_enum, ignore_case = true, default_value_t = PowerUnit::default())]
+    power_unit: PowerUnit,
+
+    /// Unit the time remaining and time to the charge limit are published in
+    #[arg(long, env = "BATTERY_DAEMON_DURATION_UNIT", value_enum, default_value_t = DurationUnit::default())]
+    duration_unit: DurationUnit,
+
+    /// Format of the timestamps in payloads, like when the battery was last
+    /// read, for consumers with rigid parsers
+    #[arg(long, env = "BATTERY_DAEMON_TIMESTAMP_FORMAT", value_enum, default_value_t = TimestampFormat::default())]
+    timestamp_format: TimestampFormat,
+
+    /// Round published values, of the batteries, UPSes and peripherals, to
+    /// this many decimals before comparing them with the last published
+    /// ones, so noise in the last digits does not trigger a publish
+    #[arg(long, env = "BATTERY_DAEMON_PRECISION", value_name = "DECIMALS")]
+    precision: Option<u8>,
+
+    /// Thermal zone type, hwmon label or input path to read the battery
+    /// temperature from when the battery reports none; defaults to the first
+    /// one whose name mentions a battery
+    #[arg(
+        long,
+        env = "BATTERY_DAEMON_TEMPERATURE_SENSOR",
+        value_name = "NAME|PATH"
+    )]
+    temperature_sensor: Option<String>,
+
+    /// Publish 0% / Unknown readings when no battery can be read, instead of
+    /// marking the entities unavailable
+    #[arg(long, env = "BATTERY_DAEMON_PUBLISH_UNKNOWN")]
+    publish_unknown: bool,
+
+    /// Where battery readings come from
+    #[arg(long, env = "BATTERY_DAEMON_BACKEND", value_enum, default_value_t = Backend::default())]
+    backend: Backend,
+
+    /// Also publish the charge of connected Bluetooth devices, read from BlueZ
+    /// (Linux only)
+    #[arg(long, env = "BATTERY_DAEMON_BLUETOOTH")]
+    bluetooth: bool,
+
+    /// Also publish the power profile from power-profiles-daemon and whether
+    /// the lid is closed, read from logind (Linux only)
+    #[arg(long, env = "BATTERY_DAEMON_PLATFORM")]
+    platform: bool,
+
+    /// Also publish the charge of peripherals UPower knows about, like
+    /// wireless mice, keyboards and controllers (Linux only)
+    #[arg(long, env = "BATTERY_DAEMON_PERIPHERALS")]
+    peripherals: bool,
+
+    /// Also publish the charge of HID devices the kernel reports directly,
+    /// like game controllers, for desktops without UPower (Linux only)
+    #[arg(long, env = "BATTERY_DAEMON_HID")]
+    hid: bool,
+
+    /// For machines without a battery, like desktops: publish only whether
+    /// AC is present, what powers the machine and when it booted, instead of
+    /// an empty battery
+    #[arg(long, env = "BATTERY_DAEMON_AC_ONLY")]
+    ac_only: bool,
+
+    /// UPS to monitor through NUT's upsd, as `UPS[@HOST[:PORT]]`; repeatable
+    #[arg(long, env = "BATTERY_DAEMON_UPS", value_delimiter = ',', value_name = "UPS@HOST", value_parser = nut::parse_ups)]
+    ups: Vec<nut::Ups>,
+
+    /// URL serving the latest release, as GitHub release JSON or a plain
+    /// version, so Home Assistant can flag outdated daemons
+    #[arg(long, env = "BATTERY_DAEMON_RELEASE_URL", value_name = "URL")]
+    release_url: Option<String>,
+
+    /// Number of samples the smoothed power draw roughly averages over
+    #[arg(long, env = "BATTERY_DAEMON_RATE_SAMPLES", default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..))]
+    rate_samples: u32,
+
+    /// Charge limit in percent; publishes the estimated time to reach it while charging
+    #[arg(long, env = "BATTERY_DAEMON_CHARGE_LIMIT", value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(1..=100))]
+    charge_limit: Option<u8>,
+
+    /// Let Home Assistant set the charge behaviour, e.g. to inhibit charging
+    /// on a laptop that stays docked; it is only published otherwise
+    #[arg(long, env = "BATTERY_DAEMON_CONTROL_CHARGE_BEHAVIOUR")]
+    control_charge_behaviour: bool,
+
+    /// Let Home Assistant and `<topic>/charge/set` set the charge end
+    /// threshold; it is only published otherwise
+    #[arg(long, env = "BATTERY_DAEMON_ALLOW_THRESHOLD_WRITES")]
+    allow_threshold_writes: bool,
+
+    /// Also publish what the backend read, before any normalizing, to
+    /// `<topic>/raw`, to diagnose hardware reporting odd values
+    #[arg(long, env = "BATTERY_DAEMON_DEBUG_TOPIC")]
+    debug_topic: bool,
+
+    /// Poll on wall-clock boundaries, e.g. on every full minute, instead of
+    /// counting from startup, so samples of many hosts line up
+    #[arg(long, env = "BATTERY_DAEMON_ALIGN")]
+    align: bool,
+
+    /// Seconds between reads of the battery, UPSes and peripherals; sources
+    /// that announce changes themselves are only read for heartbeats
+    #[arg(
+        long,
+        env = "BATTERY_DAEMON_INTERVAL",
+        value_name = "SECONDS",
+        default_value_t = 60,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    interval: u64,
+
+    /// Cron expression to read and publish the battery on instead of every
+    /// `--interval`, e.g. `*/5 * * * *`, in local time. Runs further apart
+    /// than `--heartbeat` are filled in by heartbeats
+    #[arg(
+        long,
+        env = "BATTERY_DAEMON_SCHEDULE",
+        value_name = "CRON",
+        value_parser = cron::parse_schedule,
+        conflicts_with_all = ["interval", "align", "fast_interval", "slow_interval"]
+    )]
+    schedule: Option<cron::Schedule>,
+
+    /// Seconds between reads while discharging below `--fast-below`
+    #[arg(
+        long,
+        env = "BATTERY_DAEMON_FAST_INTERVAL",
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    fast_interval: Option<u64>,
+
+    /// Charge in percent below which a discharging battery is read every
+    /// `--fast-interval`
+    #[arg(
+        long,
+        env = "BATTERY_DAEMON_FAST_BELOW",
+        value_name = "PERCENT",
+        default_value_t = 20,
+        value_parser = clap::value_parser!(u8).range(1..=100)
+    )]
+    fast_below: u8,
+
+    /// Seconds between reads while full or held at a charge limit on AC, at
+    /// most the heartbeat
+    #[arg(
+        long,
+        env = "BATTERY_DAEMON_SLOW_INTERVAL",
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    slow_interval: Option<u64>,
+
+    /// Percentage points the charge has to move before a new state is
+    /// published; changes like charging to discharging always are
+    #[arg(
+        long,
+        env = "BATTERY_DAEMON_MIN_CHANGE",
+        value_name = "PERCENT",
+        default_value_t = 0.0
+    )]
+    min_change: f32,
+
+    /// Seconds after which an unchanged state is published again, so a
+    /// steady battery can be told from a daemon that went away; Home
+    /// Assistant marks entities unavailable after two missed heartbeats
+    #[arg(
+        long,
+        env = "BATTERY_DAEMON_HEARTBEAT",
+        value_name = "SECONDS",
+        default_value_t = 300,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    heartbeat: u64,
+
+    /// Read the battery and print every message with its topic instead of
+    /// connecting to the broker
+    #[arg(long, env = "BATTERY_DAEMON_DRY_RUN")]
+    dry_run: bool,
+
+    /// Print every discovery topic and payload that would be published, with
+    /// the config applied, and exit, to diff what Home Assistant receives
+    /// before and after a change
+    #[arg(
+        long,
+        env = "BATTERY_DAEMON_PRINT_DISCOVERY",
+        conflicts_with = "no_discovery"
+    )]
+    print_discovery: bool,
+
+    /// Log more: once for every message sent, twice for everything.
+    /// RUST_LOG, e.g. RUST_LOG=rumqttc=debug,info, wins over -v and -q
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Log less: once for only problems, twice for only errors, three times
+    /// for nothing
+    #[arg(short, long, action = ArgAction::Count, global = true, conflicts_with = "verbose")]
+    quiet: u8,
+
+    /// How much to log when neither -v nor -q is given, e.g. for service
+    /// units
+    #[arg(long, env = "BATTERY_DAEMON_LOG_LEVEL", value_enum, global = true)]
+    log_level: Option<LogLevel>,
+
+    /// How log lines are written to stderr
+    #[arg(
+        long,
+        env = "BATTERY_DAEMON_LOG_FORMAT",
+        value_enum,
+        default_value_t = LogFormat::default(),
+        global = true
+    )]
+    log_format: LogFormat,
+}
+
+fn parse_key_val(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() && !value.is_empty() => {
+            Ok((key.to_string(), value.to_string()))
+        }
+        _ => Err(format!("expected KEY=VALUE, got {:?}", s)),
+    }
+}
+
+#[derive(Subcommand, Clone)]
+enum Command {
+    /// Monitor the battery and publish it until stopped; the default without
+    /// a subcommand
+    Run,
+    /// Validate the options and config file, including the topics, intervals
+    /// and thresholds, without reading the battery or connecting to the
+    /// broker; exits non-zero if anything is off
+    Check,
+    /// Round-trip a canary message through the broker, validate the discovery
+    /// configs and exit non-zero if anything failed
+    Verify,
+    /// Validate the discovery configs and print them without connecting to
+    /// the broker; exits non-zero if any is invalid
+    CheckDiscovery,
+    /// Clear this host's retained discovery configs so Home Assistant drops
+    /// its entities
+    RemoveDiscovery,
+    /// Publish the discovery configs once and exit when the broker has
+    /// acknowledged them, without reading the battery
+    Discover,
+    /// Read the battery, publish it once and exit when the broker has
+    /// acknowledged it: 0 on success, 1 if publishing failed, 3 without a
+    /// battery
+    Once {
+        /// Also publish the discovery configs
+        #[arg(long)]
+        discovery: bool,
+    },
+    /// Write a commented config file with every option at its default to
+    /// $XDG_CONFIG_HOME/battery-monitor-daemon/config.toml
+    InitConfig {
+        /// Print the config instead of writing it
+        #[arg(long)]
+        stdout: bool,
+        /// Replace an existing config file
+        #[arg(long, conflicts_with = "stdout")]
+        force: bool,
+    },
 }
 
-#[derive(PartialEq, Serialize, Clone, Copy)]
+#[derive(PartialEq, Serialize, Clone)]
 struct ChargeInfo {
     percentage: f32,
     #[serde(with = "StateDef")]
     state: State,
+    energy_rate: f32,
+    /// Energy stored right now, in Wh.
+    energy_now: f32,
+    energy_full: f32,
+    energy_full_design: f32,
+    voltage: f32,
+    /// Full capacity as a percentage of design capacity.
+    health: Option<f32>,
+    cycle_count: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time_to_target: Option<f32>,
+    /// `energy_rate` averaged over recent samples, which flaps less than the
+    /// instantaneous value.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    energy_rate_smoothed: Option<f32>,
+    /// Seconds until empty while discharging, or until the charge end
+    /// threshold (or full) while charging, fitted to recent samples.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time_remaining: Option<f32>,
+    /// How well the samples fit a straight line, 0-100.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time_remaining_confidence: Option<f32>,
+    /// Whether the full capacity reported at recent full charges spreads so
+    /// far, or swings back and forth, that the gas gauge needs recalibrating.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    needs_calibration: Option<bool>,
+    /// Spread of those capacities in percent of their mean.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    energy_full_spread: Option<f32>,
+    /// Whether a mains adapter is plugged in, when the machine reports one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ac_connected: Option<bool>,
+    /// What the machine charges from, e.g. "mains" or "usb_pd".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    charger_type: Option<String>,
+    /// Amperes and volts the adapter may draw, read from the charger.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input_current_limit: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input_voltage_limit: Option<f32>,
+    /// e.g. "balanced" or "performance", with `--platform`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    power_profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lid_closed: Option<bool>,
+    /// RFC 3339 time the machine booted, with `--ac-only`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    boot_time: Option<Timestamp>,
+    /// Charge level in percent below which the firmware starts charging, on
+    /// hardware that lets it be set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    charge_start_threshold: Option<u8>,
+    /// Charge level in percent at which the firmware stops charging, on
+    /// hardware that lets it be set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    charge_end_threshold: Option<u8>,
+    /// The kernel's coarse charge level, e.g. "Low" or "Critical". Several
+    /// packs report the most severe level of any of them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    capacity_level: Option<String>,
+    /// What the firmware does while on AC, e.g. "auto" or "inhibit-charge".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    charge_behaviour: Option<String>,
+    /// Amperes, negative while discharging; read from IOKit on macOS and
+    /// derived from the power and voltage elsewhere.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current: Option<f32>,
+    /// Watts into the battery, negative while discharging.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    power: Option<f32>,
+    /// The battery condition macOS reports, e.g. "Normal".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    condition: Option<String>,
+    /// Identify the pack, so a swap shows up in the data. Left out of the
+    /// combined reading of several packs.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vendor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    serial_number: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    technology: Option<String>,
+    /// Per-pack readings keyed by kernel name, left out of the payload when
+    /// the machine has a single battery.
+    #[serde(skip_serializing_if = "is_single")]
+    batteries: BTreeMap<String, ChargeInfo>,
+}
+
+/// What gets published to the state topic: the reading plus when it was
+/// taken, kept out of `ChargeInfo` so the time alone never counts as a change.
+#[derive(Serialize)]
+struct StatePayload<'a> {
+    #[serde(flatten)]
+    info: &'a ChargeInfo,
+    last_updated: Timestamp,
+}
+
+/// The kernel's capacity levels from most to least severe, as the options of
+/// the capacity level sensor.
+const CAPACITY_LEVELS: &[&str] = &["critical", "low", "normal", "high", "full"];
+
+/// The profiles power-profiles-daemon offers.
+const POWER_PROFILES: &[&str] = &["power-saver", "balanced", "performance"];
+
+fn is_single(batteries: &BTreeMap<String, ChargeInfo>) -> bool {
+    batteries.len() <= 1
+}
+
+/// Published to the diagnostics topic whenever reading the battery starts or
+/// keeps failing, or recovers.
+#[derive(Serialize)]
+struct ReadDiagnostics<'a> {
+    consecutive_failures: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error_at: Option<Timestamp>,
+    /// The backend that answered the last successful read, e.g. `sysfs`
+    /// when `auto` found no UPower.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backend: Option<&'a str>,
+}
+
+/// Rounds `value` to `decimals` decimal places.
+fn round_to(value: f32, decimals: u8) -> f32 {
+    let factor = 10f32.powi(i32::from(decimals));
+    (value * factor).round() / factor
+}
+
+impl ChargeInfo {
+    fn round(&mut self, decimals: u8) {
+        self.percentage = round_to(self.percentage, decimals);
+        self.energy_rate = round_to(self.energy_rate, decimals);
+        self.energy_now = round_to(self.energy_now, decimals);
+        self.energy_full = round_to(self.energy_full, decimals);
+        self.energy_full_design = round_to(self.energy_full_design, decimals);
+        self.voltage = round_to(self.voltage, decimals);
+        self.health = self.health.map(|v| round_to(v, decimals));
+        self.time_to_target = self.time_to_target.map(|v| round_to(v, decimals));
+        self.current = self.current.map(|v| round_to(v, decimals));
+        self.power = self.power.map(|v| round_to(v, decimals));
+        self.energy_rate_smoothed = self.energy_rate_smoothed.map(|v| round_to(v, decimals));
+        self.time_remaining = self.time_remaining.map(|v| round_to(v, decimals));
+        self.energy_full_spread = self.energy_full_spread.map(|v| round_to(v, decimals));
+        self.time_remaining_confidence = self
+            .time_remaining_confidence
+            .map(|v| round_to(v, decimals));
+        for battery in self.batteries.values_mut() {
+            battery.round(decimals);
+        }
+    }
+
+    /// The reading in the units picked with `--percent-unit`, `--power-unit`
+    /// and `--duration-unit`, for publishing; readings are kept in %, W and
+    /// s otherwise.
+    fn in_units(&self, args: &Args) -> ChargeInfo {
+        let ratio = |value: f32| args.percent_unit.convert(value);
+        let power = |value: f32| args.power_unit.convert(value);
+        let duration = |value: f32| args.duration_unit.convert(value);
+        ChargeInfo {
+            percentage: ratio(self.percentage),
+            health: self.health.map(ratio),
+            time_remaining_confidence: self.time_remaining_confidence.map(ratio),
+            energy_full_spread: self.energy_full_spread.map(ratio),
+            energy_rate: power(self.energy_rate),
+            energy_rate_smoothed: self.energy_rate_smoothed.map(power),
+            power: self.power.map(power),
+            time_remaining: self.time_remaining.map(duration),
+            time_to_target: self.time_to_target.map(duration),
+            boot_time: self
+                .boot_time
+                .map(|boot_time| boot_time.in_format(args.timestamp_format)),
+            batteries: self
+                .batteries
+                .iter()
+                .map(|(name, battery)| (name.clone(), battery.in_units(args)))
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Whether the reading changed enough since `prev` to publish: the charge
+    /// moved by at least `min_change` percent, or something other than a
+    /// measurement changed, like the state. Any change counts without a
+    /// minimum.
+    fn changed_from(&self, prev: &ChargeInfo, min_change: f32) -> bool {
+        if min_change <= 0.0 {
+            return self != prev;
+        }
+        (self.percentage - prev.percentage).abs() >= min_change
+            || self.without_measurements() != prev.without_measurements()
+    }
+
+    /// Keeps the value of the sensor `key` from `prev`; only the numeric
+    /// sensors can be held.
+    fn hold(&mut self, prev: &ChargeInfo, key: &str) {
+        match key {
+            "percentage" => self.percentage = prev.percentage,
+            "energy_rate" => self.energy_rate = prev.energy_rate,
+            "energy_now" => self.energy_now = prev.energy_now,
+            "energy_full" => self.energy_full = prev.energy_full,
+            "energy_full_design" => self.energy_full_design = prev.energy_full_design,
+            "voltage" => self.voltage = prev.voltage,
+            "health" => self.health = prev.health,
+            "cycle_count" => self.cycle_count = prev.cycle_count,
+            "current" => self.current = prev.current,
+            "power" => self.power = prev.power,
+            "energy_rate_smoothed" => self.energy_rate_smoothed = prev.energy_rate_smoothed,
+            "time_remaining" => self.time_remaining = prev.time_remaining,
+            "time_remaining_confidence" => {
+                self.time_remaining_confidence = prev.time_remaining_confidence
+            }
+            "energy_full_spread" => self.energy_full_spread = prev.energy_full_spread,
+            "input_current_limit" => self.input_current_limit = prev.input_current_limit,
+            "input_voltage_limit" => self.input_voltage_limit = prev.input_voltage_limit,
+            _ => return,
+        }
+        for (name, battery) in &mut self.batteries {
+            if let Some(prev) = prev.batteries.get(name) {
+                battery.hold(prev, key);
+            }
+        }
+    }
+
+    /// A copy with the values that drift between every read cleared.
+    fn without_measurements(&self) -> ChargeInfo {
+        let mut info = self.clone();
+        info.percentage = 0.0;
+        info.energy_rate = 0.0;
+        info.energy_now = 0.0;
+        info.voltage = 0.0;
+        info.time_to_target = None;
+        info.energy_rate_smoothed = None;
+        info.time_remaining = None;
+        info.time_remaining_confidence = None;
+        info.input_current_limit = None;
+        info.input_voltage_limit = None;
+        info.current = None;
+        info.power = None;
+        for battery in info.batteries.values_mut() {
+            *battery = battery.without_measurements();
+        }
+        info
+    }
+
+    /// Fills in `power` and, where the backend reports none, `current`, both
+    /// signed by the direction of the flow.
+    fn sign_flows(&mut self) {
+        let sign = if self.state == State::Discharging {
+            -1.0
+        } else {
+            1.0
+        };
+        let power = sign * self.energy_rate.abs();
+        self.power = Some(power);
+        if self.current.is_none() && self.voltage > 0.0 {
+            self.current = Some(power / self.voltage);
+        }
+        for battery in self.batteries.values_mut() {
+            battery.sign_flows();
+        }
+    }
+
+    /// Keys the per-pack readings by serial number instead of kernel name, so
+    /// a pack keeps its topics when it moves to another slot. Packs without
+    /// a serial, or sharing one, keep their kernel name.
+    fn key_by_serial(&mut self) {
+        let mut batteries = BTreeMap::new();
+        for (name, pack) in mem::take(&mut self.batteries) {
+            let serial = pack
+                .serial_number
+                .as_deref()
+                .map(sanitize_object_id)
+                .filter(|serial| !serial.is_empty() && !batteries.contains_key(serial));
+            batteries.insert(serial.unwrap_or(name), pack);
+        }
+        self.batteries = batteries;
+    }
+
+    /// Aggregates several packs into one reading, combining their charge as
+    /// `aggregate` says.
+    fn combine(packs: &[&ChargeInfo], aggregate: Aggregate) -> ChargeInfo {
+        let mut total = ChargeInfo::unknown();
+        if packs.is_empty() {
+            return total;
+        }
+        if let [only] = packs {
+            total.vendor = only.vendor.clone();
+            total.model = only.model.clone();
+            total.serial_number = only.serial_number.clone();
+            total.technology = only.technology.clone();
+        }
+        let count = packs.len() as f32;
+        let sum = |field: fn(&ChargeInfo) -> f32| packs.iter().map(|p| field(p)).sum::<f32>();
+
+        total.energy_now = sum(|p| p.energy_now);
+        total.energy_full = sum(|p| p.energy_full);
+        total.energy_full_design = sum(|p| p.energy_full_design);
+        total.energy_rate = sum(|p| p.energy_rate);
+        total.voltage = sum(|p| p.voltage) / count;
+        total.percentage = match aggregate {
+            Aggregate::Capacity if total.energy_full > 0.0 => {
+                sum(|p| p.percentage * p.energy_full) / total.energy_full
+            }
+            Aggregate::Capacity | Aggregate::Mean => sum(|p| p.percentage) / count,
+            Aggregate::Min => packs
+                .iter()
+                .map(|p| p.percentage)
+                .fold(f32::INFINITY, f32::min),
+        };
+        total.health = (total.energy_full_design > 0.0)
+            .then(|| total.energy_full / total.energy_full_design * 100.0);
+        total.cycle_count = packs.iter().filter_map(|p| p.cycle_count).max();
+        total.charge_start_threshold = packs.iter().find_map(|p| p.charge_start_threshold);
+        total.charge_end_threshold = packs.iter().find_map(|p| p.charge_end_threshold);
+        total.charge_behaviour = packs.iter().find_map(|p| p.charge_behaviour.clone());
+        total.capacity_level = packs
+            .iter()
+            .filter_map(|p| p.capacity_level.clone())
+            .min_by_key(|level| {
+                CAPACITY_LEVELS
+                    .iter()
+                    .position(|known| level.eq_ignore_ascii_case(known))
+                    .unwrap_or(CAPACITY_LEVELS.len())
+            });
+
+        let any = |state: State| packs.iter().any(|p| p.state == state);
+        let all = |state: State| packs.iter().all(|p| p.state == state);
+        total.state = if any(State::Charging) {
+            State::Charging
+        } else if any(State::Discharging) {
+            State::Discharging
+        } else if all(State::Full) {
+            State::Full
+        } else if all(State::Empty) {
+            State::Empty
+        } else {
+            State::Unknown
+        };
+        total
+    }
+
+    fn unknown() -> ChargeInfo {
+        ChargeInfo {
+            percentage: 0.0,
+            state: State::Unknown,
+            energy_rate: 0.0,
+            energy_now: 0.0,
+            energy_full: 0.0,
+            energy_full_design: 0.0,
+            voltage: 0.0,
+            health: None,
+            cycle_count: None,
+            time_to_target: None,
+            energy_rate_smoothed: None,
+            time_remaining: None,
+            time_remaining_confidence: None,
+            needs_calibration: None,
+            energy_full_spread: None,
+            ac_connected: None,
+            charger_type: None,
+            input_current_limit: None,
+            input_voltage_limit: None,
+            power_profile: None,
+            lid_closed: None,
+            boot_time: None,
+            charge_start_threshold: None,
+            charge_end_threshold: None,
+            capacity_level: None,
+            charge_behaviour: None,
+            current: None,
+            power: None,
+            condition: None,
+            vendor: None,
+            model: None,
+            serial_number: None,
+            technology: None,
+            batteries: BTreeMap::new(),
+        }
+    }
+}
+
+/// Details about the pack that rarely change, published to
+/// `<topic>/attributes` and attached to the main sensor in Home Assistant.
+#[derive(PartialEq, Serialize, Clone, Default)]
+struct BatteryAttributes {
+    vendor: Option<String>,
+    model: Option<String>,
+    serial_number: Option<String>,
+    technology: Option<String>,
+    cycle_count: Option<u32>,
+    /// Degrees Celsius.
+    temperature: Option<f32>,
+    /// The charge thresholds in effect, so a charge limit can be checked
+    /// next to the charge.
+    charge_start_threshold: Option<u8>,
+    charge_end_threshold: Option<u8>,
+    /// The backend the reading came from.
+    backend: Option<String>,
+}
+
+/// Charging rate in percent per second, learned separately for each 10% band
+/// since charging slows down as the pack approaches full.
+struct ChargeCurve {
+    rates: [Option<f32>; 10],
+}
+
+impl ChargeCurve {
+    fn new() -> ChargeCurve {
+        ChargeCurve { rates: [None; 10] }
+    }
+
+    fn band(percentage: f32) -> usize {
+        ((percentage / 10.0) as usize).min(9)
+    }
+
+    fn learn(&mut self, from: f32, to: f32, elapsed: Duration) {
+        if to <= from || elapsed.is_zero() {
+            return;
+        }
+        let rate = (to - from) / elapsed.as_secs_f32();
+        let band = &mut self.rates[Self::band(from)];
+        *band = Some(match *band {
+            Some(prev) => prev * 0.7 + rate * 0.3,
+            None => rate,
+        });
+    }
+
+    /// Seconds until `target` is reached, or `None` until a rate has been learned.
+    fn eta(&self, percentage: f32, target: f32) -> Option<f32> {
+        if percentage >= target {
+            return Some(0.0);
+        }
+        let known: Vec<f32> = self.rates.iter().flatten().copied().collect();
+        if known.is_empty() {
+            return None;
+        }
+        let fallback = known.iter().sum::<f32>() / known.len() as f32;
+        let mut seconds = 0.0;
+        let mut current = percentage;
+        while current < target {
+            let band = Self::band(current);
+            let band_end = ((band + 1) as f32 * 10.0).min(target);
+            seconds += (band_end - current) / self.rates[band].unwrap_or(fallback);
+            current = band_end;
+        }
+        Some(seconds)
+    }
+}
+
+/// When each sensor with a `periodic` or `both` publish policy last had its
+/// value taken.
+struct PublishSchedule {
+    taken: BTreeMap<String, time::Instant>,
+}
+
+impl PublishSchedule {
+    fn new() -> PublishSchedule {
+        PublishSchedule {
+            taken: BTreeMap::new(),
+        }
+    }
+
+    /// Holds back the new values of `periodic` sensors until their period is
+    /// up, returning whether the period of any sensor is up so the reading
+    /// gets published even without a change.
+    fn apply(
+        &mut self,
+        sensors: &BTreeMap<String, SensorOverride>,
+        value: &mut ChargeInfo,
+        prev: &ChargeInfo,
+    ) -> bool {
+        let mut due = false;
+        for (key, sensor) in sensors {
+            let Some(period) = sensor.period.map(Duration::from_secs) else {
+                continue;
+            };
+            if sensor.publish == PublishPolicy::OnChange {
+                continue;
+            }
+            let elapsed = self
+                .taken
+                .get(key)
+                .is_none_or(|taken| taken.elapsed() >= period);
+            if elapsed {
+                self.taken.insert(key.clone(), time::Instant::now());
+                due = true;
+            } else if sensor.publish == PublishPolicy::Periodic {
+                value.hold(prev, key);
+            }
+        }
+        due
+    }
+}
+
+/// How closely the battery needs watching, which picks the poll interval
+/// when `--fast-interval` or `--slow-interval` is given.
+#[derive(Clone, Copy, PartialEq)]
+enum PollTier {
+    /// Discharging below `--fast-below`.
+    Fast,
+    Normal,
+    /// Full, or held at a charge limit, on AC.
+    Slow,
+}
+
+impl PollTier {
+    fn of(info: &ChargeInfo, args: &Args) -> PollTier {
+        let on_ac = info.ac_connected != Some(false);
+        if info.state == State::Discharging && info.percentage < f32::from(args.fast_below) {
+            PollTier::Fast
+        } else if on_ac && matches!(info.state, State::Full | State::Unknown) {
+            PollTier::Slow
+        } else {
+            PollTier::Normal
+        }
+    }
+
+    /// The interval of this tier, or `None` to poll as usual. The slow one
+    /// is capped at the heartbeat, so entities never expire.
+    fn interval(self, args: &Args) -> Option<Duration> {
+        let seconds = match self {
+            PollTier::Fast => args.fast_interval,
+            PollTier::Normal => None,
+            PollTier::Slow => args.slow_interval,
+        };
+        Some(Duration::from_secs(seconds?).min(heartbeat(args)))
+    }
+}
+
+/// Exponentially weighted moving average of the power draw, restarted whenever
+/// the battery switches between charging and discharging.
+struct RateAverage {
+    alpha: f32,
+    average: Option<f32>,
+    state: State,
+}
+
+impl RateAverage {
+    /// Weights samples like a simple moving average over `samples` would.
+    fn new(samples: u32) -> RateAverage {
+        RateAverage {
+            alpha: 2.0 / (samples as f32 + 1.0),
+            average: None,
+            state: State::Unknown,
+        }
+    }
+
+    fn update(&mut self, rate: f32, state: State) -> f32 {
+        if state != self.state {
+            self.state = state;
+            self.average = None;
+        }
+        let average = match self.average {
+            Some(prev) => prev + self.alpha * (rate - prev),
+            None => rate,
+        };
+        self.average = Some(average);
+        average
+    }
+}
+
+/// Estimates the time remaining from a straight line fitted to the charge
+/// over the last `REMAINING_WINDOW`, restarted whenever the state changes.
+struct RemainingTime {
+    samples: VecDeque<(time::Instant, f32)>,
+    state: State,
+}
+
+/// Tracks the full capacity reported at each full charge, to tell when the
+/// gas gauge has drifted.
+struct Calibration {
+    fulls: VecDeque<f32>,
+    was_full: bool,
+}
+
+/// Full charges remembered for the calibration check.
+const CALIBRATION_CHARGES: usize = 6;
+
+/// Spread of the full capacity, in percent, beyond which it needs
+/// recalibrating.
+const CALIBRATION_MAX_SPREAD: f32 = 5.0;
+
+impl Calibration {
+    fn new() -> Calibration {
+        Calibration {
+            fulls: VecDeque::new(),
+            was_full: false,
+        }
+    }
+
+    /// Whether the gauge needs calibrating and the spread, once three full
+    /// charges have been seen.
+    fn update(&mut self, info: &ChargeInfo) -> Option<(bool, f32)> {
+        let full = info.state == State::Full;
+        if full && !self.was_full && info.energy_full > 0.0 {
+            self.fulls.push_back(info.energy_full);
+            if self.fulls.len() > CALIBRATION_CHARGES {
+                self.fulls.pop_front();
+            }
+        }
+        self.was_full = full;
+        if self.fulls.len() < 3 {
+            return None;
+        }
+        let mean = self.fulls.iter().sum::<f32>() / self.fulls.len() as f32;
+        let (min, max) = self
+            .fulls
+            .iter()
+            .fold((f32::INFINITY, 0.0f32), |(min, max), v| {
+                (min.min(*v), max.max(*v))
+            });
+        let spread = (max - min) / mean * 100.0;
+        // Capacity only ever wears down, so repeated rises mean the gauge is
+        // guessing.
+        let rises = self
+            .fulls
+            .iter()
+            .zip(self.fulls.iter().skip(1))
+            .filter(|(before, after)| *after > *before && (*after - *before) / mean > 0.01)
+            .count();
+        Some((spread > CALIBRATION_MAX_SPREAD || rises >= 2, spread))
+    }
+}
+
+/// How far back samples count towards the remaining time.
+const REMAINING_WINDOW: Duration = Duration::from_secs(20 * 60);
+
+/// Samples needed before the confidence can reach 100.
+const REMAINING_FULL_SAMPLES: usize = 10;
+
+impl RemainingTime {
+    fn new() -> RemainingTime {
+        RemainingTime {
+            samples: VecDeque::new(),
+            state: State::Unknown,
+        }
+    }
+
+    /// Seconds until `target` percent and the confidence in that, or `None`
+    /// while the charge is not moving towards it.
+    fn update(&mut self, percentage: f32, state: State, target: f32) -> Option<(f32, f32)> {
+        let now = time::Instant::now();
+        if state != self.state {
+            self.state = state;
+            self.samples.clear();
+        }
+        self.samples.push_back((now, percentage));
+        while self
+            .samples
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > REMAINING_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+        if self.samples.len() < 3 {
+            return None;
+        }
+
+        // Least squares fit of percentage over seconds since the first sample.
+        let start = self.samples[0].0;
+        let points: Vec<(f32, f32)> = self
+            .samples
+            .iter()
+            .map(|(at, p)| (at.duration_since(start).as_secs_f32(), *p))
+            .collect();
+        let n = points.len() as f32;
+        let mean_x = points.iter().map(|(x, _)| x).sum::<f32>() / n;
+        let mean_y = points.iter().map(|(_, y)| y).sum::<f32>() / n;
+        let sxx: f32 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+        let sxy: f32 = points
+            .iter()
+            .map(|(x, y)| (x - mean_x) * (y - mean_y))
+            .sum();
+        let syy: f32 = points.iter().map(|(_, y)| (y - mean_y).powi(2)).sum();
+        if sxx <= 0.0 || syy <= 0.0 {
+            return None;
+        }
+        let slope = sxy / sxx;
+        let seconds = match state {
+            State::Discharging if slope < 0.0 => percentage / -slope,
+            State::Charging if slope > 0.0 => (target - percentage).max(0.0) / slope,
+            _ => return None,
+        };
+        let fit = sxy * sxy / (sxx * syy);
+        let coverage = (self.samples.len() as f32 / REMAINING_FULL_SAMPLES as f32).min(1.0);
+        Some((seconds, fit * coverage * 100.0))
+    }
 }
 
 #[derive(Serialize)]
@@ -45,42 +1225,180 @@ enum StateDef {
 #[derive(PartialEq, Serialize)]
 struct DiscoveryPayload {
     name: String,
-    device_class: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
     state_topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command_topic: Option<String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
     unit_of_measurement: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
     value_template: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entity_category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expire_after: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    availability_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    json_attributes_topic: Option<String>,
+    unique_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device: Option<DeviceInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    origin: Option<Origin>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suggested_display_precision: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    force_update: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    enabled_by_default: Option<bool>,
+    /// The states an `enum` sensor can take.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<Vec<String>>,
 }
 
-impl DiscoveryPayload {
-    fn new(
-        name: String,
-        device_class: String,
-        state_topic: String,
-        unit_of_measurement: String,
-        value_template: String,
-    ) -> DiscoveryPayload {
-        DiscoveryPayload {
-            name,
-            device_class,
-            state_topic,
-            unit_of_measurement,
-            value_template,
+/// The Home Assistant device every entity of this host is grouped under.
+#[derive(PartialEq, Serialize, Clone)]
+struct DeviceInfo {
+    identifiers: Vec<String>,
+    name: String,
+    /// Vendor, model and serial number of the (first) battery.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    manufacturer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    serial_number: Option<String>,
+}
+
+/// What discovery needs to know about this host, taken from a single battery
+/// reading so building the configs does not read the battery again.
+#[derive(Clone)]
+struct Host {
+    object_id: String,
+    device: DeviceInfo,
+    /// Batteries with entities of their own, when there are several.
+    batteries: Vec<String>,
+}
+
+impl Host {
+    fn new(args: &Args, reading: Option<&Reading>) -> Host {
+        let attributes = reading
+            .map(|(_, attributes)| attributes.clone())
+            .unwrap_or_default();
+        let object_id = object_id(args, attributes.serial_number.as_deref());
+        let batteries = reading
+            .map(|(info, _)| {
+                let mut info = info.clone();
+                if args.stable_id {
+                    info.key_by_serial();
+                }
+                info.batteries
+            })
+            .filter(|batteries| batteries.len() > 1)
+            .map(|batteries| batteries.into_keys().collect())
+            .unwrap_or_default();
+        Host {
+            device: DeviceInfo {
+                identifiers: vec![object_id.clone()],
+                name: device_name(args, &object_id),
+                manufacturer: attributes.vendor,
+                model: attributes.model,
+                serial_number: attributes.serial_number,
+            },
+            object_id,
+            batteries,
+        }
+    }
+
+    /// Reads the battery once, through the configured backend.
+    async fn read(args: &Args) -> Host {
+        let reading = power_source(args).read(&read_options(args)).await.ok();
+        Host::new(args, reading.as_ref())
+    }
+}
+
+/// Identifies the software that published a discovery config.
+#[derive(PartialEq, Serialize, Clone)]
+struct Origin {
+    name: String,
+    sw_version: String,
+    support_url: String,
+}
+
+impl Origin {
+    fn new() -> Origin {
+        Origin {
+            name: String::from(env!("CARGO_PKG_NAME")),
+            sw_version: String::from(env!("CARGO_PKG_VERSION")),
+            support_url: String::from(env!("CARGO_PKG_REPOSITORY")),
         }
     }
 }
 
+/// One entity inside a device-based discovery config.
+#[derive(Serialize)]
+struct DeviceComponent {
+    platform: String,
+    #[serde(flatten)]
+    payload: DiscoveryPayload,
+}
+
+/// Home Assistant's device-based discovery config, describing every entity of
+/// the device in a single retained message.
+#[derive(Serialize)]
+struct DeviceDiscoveryPayload {
+    device: DeviceInfo,
+    origin: Origin,
+    components: BTreeMap<String, DeviceComponent>,
+}
+
 struct DiscoveryPayloadBuilder {
     name: String,
-    device_class: String,
+    device_class: Option<String>,
     state_topic: String,
+    command_topic: Option<String>,
+    unit_of_measurement: String,
+    value_template: String,
+    entity_category: Option<String>,
+    expire_after: Option<u64>,
+    availability_topic: Option<String>,
+    icon: Option<String>,
+    json_attributes_topic: Option<String>,
+    unique_id: String,
+    device: Option<DeviceInfo>,
+    origin: Option<Origin>,
+    suggested_display_precision: Option<u8>,
+    force_update: Option<bool>,
+    enabled_by_default: Option<bool>,
+    options: Option<Vec<String>>,
 }
 
 impl DiscoveryPayloadBuilder {
     fn new() -> DiscoveryPayloadBuilder {
         DiscoveryPayloadBuilder {
             name: String::from(""),
-            device_class: String::from(""),
+            device_class: None,
             state_topic: String::from(""),
+            command_topic: None,
+            unit_of_measurement: String::from(""),
+            value_template: String::from(""),
+            entity_category: None,
+            expire_after: None,
+            availability_topic: None,
+            icon: None,
+            json_attributes_topic: None,
+            unique_id: String::from(""),
+            device: None,
+            origin: None,
+            suggested_display_precision: None,
+            force_update: None,
+            enabled_by_default: None,
+            options: None,
         }
     }
 
@@ -90,7 +1408,7 @@ impl DiscoveryPayloadBuilder {
     }
 
     fn device_class(mut self, device_class: String) -> DiscoveryPayloadBuilder {
-        self.device_class = device_class;
+        self.device_class = Some(device_class);
         self
     }
 
@@ -99,281 +1417,2659 @@ impl DiscoveryPayloadBuilder {
         self
     }
 
-    // fn build(self) -> DiscoveryPayload {
-    //     DiscoveryPayload {
-    //         name: self.name,
-    //         device_class: self.device_class,
-    //         state_topic: self.state_topic,
-    //     }
-    // }
-}
+    fn command_topic(mut self, command_topic: String) -> DiscoveryPayloadBuilder {
+        self.command_topic = Some(command_topic);
+        self
+    }
 
-impl fmt::Display for DiscoveryPayload {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if let Ok(payload) = serde_json::to_string(self) {
-            write!(f, "{}", payload)
-        } else {
-            panic!("Failed to serialize payload")
-        }
+    fn unit_of_measurement(mut self, unit_of_measurement: String) -> DiscoveryPayloadBuilder {
+        self.unit_of_measurement = unit_of_measurement;
+        self
     }
-}
 
-#[derive(PartialEq)]
-struct DiscoveryTopic {
-    discovery_prefix: String,
-    comp: DiscoveryDevice,
-    node_id: NodeID,
-    object_id: String,
-}
+    fn value_template(mut self, value_template: String) -> DiscoveryPayloadBuilder {
+        self.value_template = value_template;
+        self
+    }
 
-impl fmt::Display for DiscoveryTopic {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match &self.node_id {
-            NodeID::Empty => write!(
-                f,
+    fn entity_category(mut self, entity_category: String) -> DiscoveryPayloadBuilder {
+        self.entity_category = Some(entity_category);
+        self
+    }
+
+    fn expire_after(mut self, expire_after: u64) -> DiscoveryPayloadBuilder {
+        self.expire_after = Some(expire_after);
+        self
+    }
+
+    fn availability_topic(mut self, availability_topic: String) -> DiscoveryPayloadBuilder {
+        self.availability_topic = Some(availability_topic);
+        self
+    }
+
+    fn icon(mut self, icon: String) -> DiscoveryPayloadBuilder {
+        self.icon = Some(icon);
+        self
+    }
+
+    fn json_attributes_topic(mut self, json_attributes_topic: String) -> DiscoveryPayloadBuilder {
+        self.json_attributes_topic = Some(json_attributes_topic);
+        self
+    }
+
+    fn unique_id(mut self, unique_id: String) -> DiscoveryPayloadBuilder {
+        self.unique_id = unique_id;
+        self
+    }
+
+    fn device(mut self, device: DeviceInfo) -> DiscoveryPayloadBuilder {
+        self.device = Some(device);
+        self
+    }
+
+    fn origin(mut self, origin: Origin) -> DiscoveryPayloadBuilder {
+        self.origin = Some(origin);
+        self
+    }
+
+    fn suggested_display_precision(mut self, precision: u8) -> DiscoveryPayloadBuilder {
+        self.suggested_display_precision = Some(precision);
+        self
+    }
+
+    fn options(mut self, options: &[&str]) -> DiscoveryPayloadBuilder {
+        self.options = Some(options.iter().map(|option| option.to_string()).collect());
+        self
+    }
+
+    /// Switches a battery sensor in %, W or s to the units picked with
+    /// `--percent-unit`, `--power-unit` and `--duration-unit`.
+    fn in_units(mut self, args: &Args) -> DiscoveryPayloadBuilder {
+        match self.unit_of_measurement.as_str() {
+            "%" if args.percent_unit == PercentUnit::Fraction => {
+                // The battery device class only takes percent.
+                if self.device_class.as_deref() == Some("battery") {
+                    self.device_class = None;
+                }
+                self.suggested_display_precision = self
+                    .suggested_display_precision
+                    .map(|precision| precision + 2);
+                self.unit_of_measurement = String::from(args.percent_unit.unit());
+            }
+            "W" => self.unit_of_measurement = String::from(args.power_unit.unit()),
+            "s" => self.unit_of_measurement = String::from(args.duration_unit.unit()),
+            _ => {}
+        }
+        self
+    }
+
+    fn apply_override(mut self, sensor: &SensorOverride) -> DiscoveryPayloadBuilder {
+        if let Some(device_class) = &sensor.device_class {
+            self.device_class = Some(device_class.clone()).filter(|class| !class.is_empty());
+        }
+        if let Some(unit_of_measurement) = &sensor.unit_of_measurement {
+            self.unit_of_measurement = unit_of_measurement.clone();
+        }
+        if let Some(value_template) = &sensor.value_template {
+            self.value_template = value_template.clone();
+        }
+        if let Some(force_update) = sensor.force_update {
+            self.force_update = Some(force_update);
+        }
+        if let Some(enabled_by_default) = sensor.enabled_by_default {
+            self.enabled_by_default = Some(enabled_by_default);
+        }
+        self
+    }
+
+    fn force_update(mut self, force_update: bool) -> DiscoveryPayloadBuilder {
+        self.force_update = Some(force_update);
+        self
+    }
+
+    fn build(self) -> DiscoveryPayload {
+        DiscoveryPayload {
+            name: self.name,
+            device_class: self.device_class,
+            state_topic: self.state_topic,
+            command_topic: self.command_topic,
+            unit_of_measurement: self.unit_of_measurement,
+            value_template: self.value_template,
+            entity_category: self.entity_category,
+            expire_after: self.expire_after,
+            availability_topic: self.availability_topic,
+            icon: self.icon,
+            json_attributes_topic: self.json_attributes_topic,
+            unique_id: self.unique_id,
+            device: self.device,
+            origin: self.origin,
+            suggested_display_precision: self.suggested_display_precision,
+            force_update: self.force_update,
+            enabled_by_default: self.enabled_by_default,
+            options: self.options,
+        }
+    }
+}
+
+impl fmt::Display for DiscoveryPayload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Ok(payload) = serde_json::to_string(self) {
+            write!(f, "{}", payload)
+        } else {
+            panic!("Failed to serialize payload")
+        }
+    }
+}
+
+#[derive(PartialEq)]
+struct DiscoveryTopic {
+    discovery_prefix: String,
+    comp: DiscoveryDevice,
+    node_id: NodeID,
+    object_id: String,
+}
+
+impl fmt::Display for DiscoveryTopic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.node_id {
+            NodeID::Empty => write!(
+                f,
                 "{}/{}/{}/config",
                 self.discovery_prefix, self.comp, self.object_id
             ),
             NodeID::Is(id) => write!(
                 f,
                 "{}/{}/{}/{}/config",
-                self.discovery_prefix, id, self.comp, self.object_id
+                self.discovery_prefix, self.comp, id, self.object_id
             ),
         }
     }
 }
 
-struct DiscoveryTopicBuilder {
-    discovery_prefix: String,
-    comp: DiscoveryDevice,
-    node_id: NodeID,
-    object_id: String,
+/// Turns a hostname like `Johns-MacBook.local` into a valid object_id like
+/// `johns-macbook_local`: lowercase, with runs of anything outside
+/// `[a-z0-9_-]` collapsed into a single underscore.
+fn sanitize_object_id(raw: &str) -> String {
+    let mut id = String::with_capacity(raw.len());
+    for c in raw.chars().flat_map(char::to_lowercase) {
+        if c.is_ascii_alphanumeric() || c == '-' {
+            id.push(c);
+        } else if !id.ends_with('_') {
+            id.push('_');
+        }
+    }
+    id.trim_matches('_').to_string()
+}
+
+fn parse_object_id(s: &str) -> Result<String, String> {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        Ok(s.to_string())
+    } else {
+        Err(String::from(
+            "must be non-empty and only contain [a-zA-Z0-9_-]",
+        ))
+    }
+}
+
+fn parse_device_name(s: &str) -> Result<String, String> {
+    if sanitize_object_id(s).is_empty() {
+        Err(String::from("must contain at least one letter or digit"))
+    } else {
+        Ok(s.to_string())
+    }
+}
+
+struct DiscoveryTopicBuilder {
+    discovery_prefix: String,
+    comp: DiscoveryDevice,
+    node_id: NodeID,
+    object_id: String,
+}
+
+impl DiscoveryTopicBuilder {
+    fn new() -> DiscoveryTopicBuilder {
+        if let Ok(hostname) = gethostname().into_string() {
+            DiscoveryTopicBuilder {
+                discovery_prefix: String::from("homeassistant"),
+                comp: DiscoveryDevice::NoneType,
+                node_id: NodeID::Empty,
+                object_id: sanitize_object_id(&hostname),
+            }
+        } else {
+            DiscoveryTopicBuilder {
+                discovery_prefix: String::from("homeassistant"),
+                comp: DiscoveryDevice::NoneType,
+                node_id: NodeID::Empty,
+                object_id: String::from(""),
+            }
+        }
+    }
+    fn build(self) -> DiscoveryTopic {
+        DiscoveryTopic {
+            discovery_prefix: self.discovery_prefix,
+            comp: self.comp,
+            node_id: self.node_id,
+            object_id: self.object_id,
+        }
+    }
+    fn comp(mut self, comp: DiscoveryDevice) -> DiscoveryTopicBuilder {
+        self.comp = comp;
+        self
+    }
+
+    fn discovery_prefix(mut self, discovery_prefix: String) -> DiscoveryTopicBuilder {
+        self.discovery_prefix = discovery_prefix;
+        self
+    }
+
+    fn node_id(mut self, node_id: NodeID) -> DiscoveryTopicBuilder {
+        self.node_id = node_id;
+        self
+    }
+
+    fn object_id(mut self, object_id: String) -> DiscoveryTopicBuilder {
+        self.object_id = object_id;
+        self
+    }
+}
+
+struct Discovery {
+    topic: DiscoveryTopic,
+    payload: DiscoveryPayload,
+}
+
+#[derive(PartialEq)]
+enum DiscoveryDevice {
+    BinarySensor,
+    Sensor,
+    Number,
+    Button,
+    Switch,
+    Select,
+    Update,
+    Device,
+    NoneType,
+}
+
+impl fmt::Display for DiscoveryDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::BinarySensor => write!(f, "binary_sensor"),
+            Self::Sensor => write!(f, "sensor"),
+            Self::Number => write!(f, "number"),
+            Self::Button => write!(f, "button"),
+            Self::Switch => write!(f, "switch"),
+            Self::Select => write!(f, "select"),
+            Self::Update => write!(f, "update"),
+            Self::Device => write!(f, "device"),
+            _ => write!(f, "none"),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+enum NodeID {
+    Empty,
+    Is(String),
+}
+
+#[derive(PartialEq, Clone)]
+struct Message {
+    topic: String,
+    payload: String,
+    retain: bool,
+}
+
+struct MessageBuilder {
+    topic: String,
+    payload: String,
+    retain: bool,
+}
+
+impl MessageBuilder {
+    fn new() -> MessageBuilder {
+        MessageBuilder {
+            topic: String::from(""),
+            payload: String::from(""),
+            retain: false,
+        }
+    }
+
+    fn build(self) -> Message {
+        Message {
+            topic: self.topic,
+            payload: self.payload,
+            retain: self.retain,
+        }
+    }
+    fn retain(mut self, retain: bool) -> MessageBuilder {
+        self.retain = retain;
+        self
+    }
+
+    fn topic(mut self, topic: String) -> MessageBuilder {
+        self.topic = topic;
+        self
+    }
+
+    fn payload(mut self, payload: String) -> MessageBuilder {
+        self.payload = payload;
+        self
+    }
+}
+
+impl From<Discovery> for MessageBuilder {
+    fn from(value: Discovery) -> MessageBuilder {
+        MessageBuilder {
+            topic: value.topic.to_string(),
+            payload: value.payload.to_string(),
+            retain: false,
+        }
+    }
+}
+
+/// Seconds without a state message after which Home Assistant should mark the
+/// entities unavailable: two missed heartbeats.
+fn expire_after(args: &Args) -> u64 {
+    (heartbeat(args) * 2).as_secs()
+}
+
+fn availability_topic(topic: &str) -> String {
+    format!("{}/availability", topic)
+}
+
+/// State of a single pack on machines with more than one.
+fn battery_state_topic(topic: &str, battery: &str) -> String {
+    format!("{}/{}/state", topic, battery)
+}
+
+/// State of a UPS read from NUT, keyed by its sanitized name.
+fn ups_state_topic(topic: &str, ups: &str) -> String {
+    format!("{}/ups/{}/state", topic, sanitize_object_id(ups))
+}
+
+fn peripheral_state_topic(topic: &str, id: &str) -> String {
+    format!("{}/peripherals/{}/state", topic, id)
+}
+
+fn attributes_topic(topic: &str) -> String {
+    format!("{}/attributes", topic)
+}
+
+/// Where Home Assistant sends a new charge end threshold.
+fn threshold_command_topic(topic: &str) -> String {
+    format!("{}/charge_end_threshold/set", topic)
+}
+
+/// Where battery-care policies are pushed as JSON, e.g. by fleet automation
+/// that does not go through Home Assistant.
+fn charge_command_topic(topic: &str) -> String {
+    format!("{}/charge/set", topic)
+}
+
+/// Where Home Assistant sends a new charge behaviour, e.g. "inhibit-charge".
+fn behaviour_command_topic(topic: &str) -> String {
+    format!("{}/charge_behaviour/set", topic)
+}
+
+/// Pressing the refresh button publishes here.
+fn refresh_command_topic(topic: &str) -> String {
+    format!("{}/refresh", topic)
+}
+
+/// Whether publishing is paused, as "ON" or "OFF".
+fn paused_topic(topic: &str) -> String {
+    format!("{}/paused", topic)
+}
+
+/// Where an event is published as soon as the firmware declares a battery
+/// critical.
+fn alert_topic(topic: &str) -> String {
+    format!("{}/alert", topic)
+}
+
+/// Why reading the battery failed, if it did.
+fn diagnostics_topic(topic: &str) -> String {
+    format!("{}/diagnostics", topic)
+}
+
+/// Unprocessed backend data, with `--debug-topic`.
+fn raw_topic(topic: &str) -> String {
+    format!("{}/raw", topic)
+}
+
+/// Installed and latest daemon version, for the update entity.
+fn version_topic(topic: &str) -> String {
+    format!("{}/version", topic)
+}
+
+fn paused_command_topic(topic: &str) -> String {
+    format!("{}/paused/set", topic)
+}
+
+/// Parses a charge end threshold sent by Home Assistant, which may send
+/// whole numbers as e.g. "80.0".
+fn parse_threshold(payload: &[u8]) -> Result<u8, String> {
+    let text = String::from_utf8_lossy(payload);
+    let value: f32 = text
+        .trim()
+        .parse()
+        .map_err(|_| format!("{:?} is not a number", text))?;
+    if !(1.0..=100.0).contains(&value) {
+        return Err(format!("{} is outside 1-100", value));
+    }
+    Ok(value.round() as u8)
+}
+
+/// A battery-care policy sent to the charge command topic.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChargeCommand {
+    /// Charge end threshold in percent.
+    charge_limit: u8,
+}
+
+/// Parses a command like `{"charge_limit": 80}` into the charge end threshold.
+fn parse_charge_command(payload: &[u8]) -> Result<u8, String> {
+    let command: ChargeCommand = serde_json::from_slice(payload).map_err(|e| {
+        format!(
+            "{:?} is not a charge command: {}",
+            String::from_utf8_lossy(payload),
+            e
+        )
+    })?;
+    if !(1..=100).contains(&command.charge_limit) {
+        return Err(format!("{} is outside 1-100", command.charge_limit));
+    }
+    Ok(command.charge_limit)
+}
+
+/// Writes a charge end threshold received over MQTT, returning whether it was
+/// applied.
+fn apply_threshold(value: u8) -> bool {
+    match sysfs::set_charge_end_threshold(value) {
+        Ok(()) => true,
+        Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+            warn!(error = %e, "not setting charge end threshold to {}%", value);
+            false
+        }
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            warn!(
+                "setting charge end threshold to {}% needs write access to \
+                 charge_control_end_threshold, usually by running as root",
+                value
+            );
+            false
+        }
+        Err(e) => {
+            warn!(error = %e, "setting charge end threshold to {}% failed", value);
+            false
+        }
+    }
+}
+
+/// Writes a charge behaviour received over MQTT if the firmware offers it,
+/// returning whether it was applied.
+fn apply_charge_behaviour(value: &str) -> bool {
+    let behaviours = sysfs::charge_behaviours();
+    if !behaviours.iter().any(|behaviour| behaviour == value) {
+        warn!(
+            "ignoring charge behaviour {:?}, expected one of {}",
+            value,
+            behaviours.join(", ")
+        );
+        return false;
+    }
+    match sysfs::set_charge_behaviour(value) {
+        Ok(()) => true,
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            warn!(
+                "setting charge behaviour to {} needs write access to \
+                 charge_behaviour, usually by running as root",
+                value
+            );
+            false
+        }
+        Err(e) => {
+            warn!(error = %e, "setting charge behaviour to {} failed", value);
+            false
+        }
+    }
+}
+
+/// Key of the sensor that keeps the bare hostname as its object id, as it
+/// predates the other sensors.
+const PRIMARY_SENSOR: &str = "percentage";
+
+/// The entities kept with `--ac-only`: those about the machine's power
+/// rather than a battery, plus the controls and diagnostics.
+const AC_ONLY_ENTITIES: &[&str] = &[
+    "ac_connected",
+    "charger_type",
+    "input_current_limit",
+    "input_voltage_limit",
+    "power_profile",
+    "lid_closed",
+    "last_updated",
+    "read_error",
+    "read_failures",
+    "refresh",
+    "paused",
+    "version",
+];
+
+/// Name of the Home Assistant device, which HA also prefixes to every entity
+/// name.
+fn device_name(args: &Args, object_id: &str) -> String {
+    let name = args
+        .name
+        .clone()
+        .or_else(|| args.device_name.clone())
+        .or_else(|| gethostname().into_string().ok())
+        .unwrap_or_else(|| object_id.to_string());
+    match &args.fleet {
+        Some(fleet) => format!("{}/{}", fleet, name),
+        None => name,
+    }
+}
+
+/// The systemd/D-Bus machine-id, which survives renaming the host.
+fn machine_id() -> Option<String> {
+    ["/etc/machine-id", "/var/lib/dbus/machine-id"]
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok())
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty())
+}
+
+/// Object id for `--stable-id`: the start of the machine-id plus the serial
+/// number of the first battery, whichever of the two exist.
+fn stable_object_id(serial: Option<&str>) -> Option<String> {
+    let serial = serial
+        .map(sanitize_object_id)
+        .filter(|serial| !serial.is_empty());
+    let machine = machine_id().map(|id| id.chars().take(12).collect::<String>());
+    match (machine, serial) {
+        (Some(machine), Some(serial)) => Some(format!("{}_{}", machine, serial)),
+        (machine, serial) => machine.or(serial),
+    }
+}
+
+/// The object id with the overrides from the command line applied, given the
+/// serial number of the first battery for `--stable-id`. A fleet prefixes
+/// it, unless it is given explicitly.
+fn object_id(args: &Args, serial: Option<&str>) -> String {
+    let mut object_id = DiscoveryTopicBuilder::new().object_id;
+    if let Some(name) = &args.device_name {
+        object_id = sanitize_object_id(name);
+    }
+    if let Some(stable) = args.stable_id.then(|| stable_object_id(serial)).flatten() {
+        object_id = stable;
+    }
+    match (&args.object_id, &args.fleet) {
+        (Some(object_id), _) => object_id.clone(),
+        (None, Some(fleet)) => format!("{}_{}", fleet, object_id),
+        (None, None) => object_id,
+    }
+}
+
+/// Discovery topic builder for `host`, with the prefix and node_id from the
+/// command line applied. A fleet becomes the node_id, unless one is given.
+fn discovery_topic_builder(args: &Args, host: &Host) -> DiscoveryTopicBuilder {
+    DiscoveryTopicBuilder::new()
+        .discovery_prefix(args.discovery_topic.clone())
+        .node_id(
+            args.node_id
+                .clone()
+                .or_else(|| args.fleet.clone())
+                .map_or(NodeID::Empty, NodeID::Is),
+        )
+        .object_id(host.object_id.clone())
+}
+
+/// A Home Assistant entity reading the `key` field of the state payload, or
+/// of one battery's entry in it.
+struct Entity {
+    key: &'static str,
+    comp: DiscoveryDevice,
+    battery: Option<String>,
+    payload: DiscoveryPayloadBuilder,
+}
+
+impl Entity {
+    fn sensor(key: &'static str, payload: DiscoveryPayloadBuilder) -> Entity {
+        Entity {
+            key,
+            comp: DiscoveryDevice::Sensor,
+            battery: None,
+            payload,
+        }
+    }
+}
+
+/// Finishes the discovery config for an entity, using `<hostname>_<key>` as
+/// its object id, suffixed with `_<battery>` (and the name with the battery)
+/// for the entities of a single pack.
+fn entity_discovery(topic: DiscoveryTopicBuilder, entity: Entity) -> Discovery {
+    let builder = topic.comp(entity.comp);
+    let mut object_id = if entity.key == PRIMARY_SENSOR {
+        builder.object_id.clone()
+    } else {
+        format!("{}_{}", builder.object_id, entity.key)
+    };
+    let mut payload = entity.payload;
+    if let Some(battery) = entity.battery {
+        object_id = format!("{}_{}", object_id, battery);
+        payload.name = format!("{} {}", payload.name, battery.to_uppercase());
+    }
+    let topic = builder.object_id(object_id.clone()).build();
+    let payload = payload.unique_id(object_id).build();
+    Discovery { topic, payload }
+}
+
+/// Every entity the options and hardware call for, before `--include-sensor`
+/// and `--exclude-sensor`.
+fn entities(args: &Args, host: &Host) -> Vec<Entity> {
+    let state_topic = format!("{}/state", args.topic);
+    let device = host.device.clone();
+    // Entities without any state, like buttons, only get these. Controls add
+    // their own state_topic, as they have no expire_after.
+    let stateless = || {
+        DiscoveryPayloadBuilder::new()
+            .device(device.clone())
+            .origin(Origin::new())
+            .availability_topic(availability_topic(&args.topic))
+    };
+    let base = || {
+        stateless()
+            .state_topic(state_topic.clone())
+            .expire_after(expire_after(args))
+    };
+    // The sensors reading a state payload published to `topic`.
+    let sensors = |topic: &str| {
+        let template = |key: &str| format!("{{{{ value_json.{} }}}}", key);
+        let reading = || base().state_topic(topic.to_string());
+        vec![
+            (
+                PRIMARY_SENSOR,
+                reading()
+                    .name(String::from("Battery"))
+                    .device_class(String::from("battery"))
+                    .unit_of_measurement(String::from("%"))
+                    .value_template(template("percentage"))
+                    .suggested_display_precision(0),
+            ),
+            (
+                "energy_rate",
+                reading()
+                    .name(String::from("Power draw"))
+                    .device_class(String::from("power"))
+                    .unit_of_measurement(String::from("W"))
+                    .value_template(template("energy_rate"))
+                    .suggested_display_precision(1),
+            ),
+            (
+                "power",
+                reading()
+                    .name(String::from("Battery power"))
+                    .device_class(String::from("power"))
+                    .unit_of_measurement(String::from("W"))
+                    .value_template(template("power"))
+                    .suggested_display_precision(1),
+            ),
+            (
+                "current",
+                reading()
+                    .name(String::from("Battery current"))
+                    .device_class(String::from("current"))
+                    .unit_of_measurement(String::from("A"))
+                    .value_template(template("current"))
+                    .suggested_display_precision(2),
+            ),
+            (
+                "energy_now",
+                reading()
+                    .name(String::from("Stored energy"))
+                    .device_class(String::from("energy_storage"))
+                    .unit_of_measurement(String::from("Wh"))
+                    .value_template(template("energy_now"))
+                    .suggested_display_precision(1),
+            ),
+            (
+                "energy_full",
+                reading()
+                    .name(String::from("Full capacity"))
+                    .device_class(String::from("energy_storage"))
+                    .unit_of_measurement(String::from("Wh"))
+                    .value_template(template("energy_full"))
+                    .entity_category(String::from("diagnostic")),
+            ),
+            (
+                "energy_full_design",
+                reading()
+                    .name(String::from("Design capacity"))
+                    .device_class(String::from("energy_storage"))
+                    .unit_of_measurement(String::from("Wh"))
+                    .value_template(template("energy_full_design"))
+                    .entity_category(String::from("diagnostic")),
+            ),
+            (
+                "health",
+                reading()
+                    .name(String::from("Health"))
+                    .unit_of_measurement(String::from("%"))
+                    .value_template(template("health"))
+                    .entity_category(String::from("diagnostic")),
+            ),
+            (
+                "cycle_count",
+                reading()
+                    .name(String::from("Cycle count"))
+                    .unit_of_measurement(String::from("cycles"))
+                    .value_template(template("cycle_count"))
+                    .entity_category(String::from("diagnostic")),
+            ),
+            (
+                "voltage",
+                reading()
+                    .name(String::from("Voltage"))
+                    .device_class(String::from("voltage"))
+                    .unit_of_measurement(String::from("V"))
+                    .value_template(template("voltage"))
+                    .entity_category(String::from("diagnostic")),
+            ),
+        ]
+        .into_iter()
+        .map(|(key, payload)| (key, payload.in_units(args)))
+        .collect::<Vec<_>>()
+    };
+
+    let mut entities: Vec<Entity> = sensors(&state_topic)
+        .into_iter()
+        .map(|(key, payload)| Entity::sensor(key, payload))
+        .collect();
+    entities.push(Entity::sensor(
+        "energy_rate_smoothed",
+        base()
+            .name(String::from("Power draw (smoothed)"))
+            .device_class(String::from("power"))
+            .unit_of_measurement(String::from("W"))
+            .value_template(String::from(
+                "{{ value_json.energy_rate_smoothed | default(None) }}",
+            ))
+            .suggested_display_precision(1)
+            .in_units(args),
+    ));
+    entities.push(Entity::sensor(
+        "time_remaining",
+        base()
+            .name(String::from("Time remaining"))
+            .device_class(String::from("duration"))
+            .unit_of_measurement(String::from("s"))
+            .value_template(String::from(
+                "{{ value_json.time_remaining | default(None) }}",
+            ))
+            .in_units(args),
+    ));
+    entities.push(Entity::sensor(
+        "time_remaining_confidence",
+        base()
+            .name(String::from("Time remaining confidence"))
+            .unit_of_measurement(String::from("%"))
+            .value_template(String::from(
+                "{{ value_json.time_remaining_confidence | default(None) }}",
+            ))
+            .entity_category(String::from("diagnostic"))
+            .in_units(args),
+    ));
+    if args.charge_limit.is_some() {
+        entities.push(Entity::sensor(
+            "time_to_target",
+            base()
+                .name(String::from("Time to charge limit"))
+                .device_class(String::from("duration"))
+                .unit_of_measurement(String::from("s"))
+                .value_template(String::from(
+                    "{{ value_json.time_to_target | default(None) }}",
+                ))
+                .in_units(args),
+        ));
+    }
+    entities.push(Entity::sensor(
+        "temperature",
+        stateless()
+            .state_topic(attributes_topic(&args.topic))
+            .name(String::from("Temperature"))
+            .device_class(String::from("temperature"))
+            .unit_of_measurement(String::from("°C"))
+            .value_template(String::from("{{ value_json.temperature | default(None) }}"))
+            .suggested_display_precision(1)
+            .entity_category(String::from("diagnostic")),
+    ));
+    entities.push(Entity {
+        key: "needs_calibration",
+        comp: DiscoveryDevice::BinarySensor,
+        battery: None,
+        payload: base()
+            .name(String::from("Needs calibration"))
+            .device_class(String::from("problem"))
+            .value_template(String::from(
+                "{{ 'ON' if value_json.needs_calibration | default(false) else 'OFF' }}",
+            ))
+            .entity_category(String::from("diagnostic")),
+    });
+    entities.push(Entity::sensor(
+        "energy_full_spread",
+        base()
+            .name(String::from("Full capacity spread"))
+            .unit_of_measurement(String::from("%"))
+            .value_template(String::from(
+                "{{ value_json.energy_full_spread | default(None) }}",
+            ))
+            .entity_category(String::from("diagnostic"))
+            .in_units(args),
+    ));
+    // Left without availability, as they matter most while no battery can be
+    // read and the other entities are unavailable.
+    let diagnostic = || {
+        DiscoveryPayloadBuilder::new()
+            .device(device.clone())
+            .origin(Origin::new())
+            .state_topic(diagnostics_topic(&args.topic))
+            .entity_category(String::from("diagnostic"))
+    };
+    entities.push(Entity::sensor(
+        "read_error",
+        diagnostic()
+            .name(String::from("Read error"))
+            .value_template(String::from(
+                "{{ value_json.last_error if value_json.consecutive_failures else 'none' }}",
+            )),
+    ));
+    entities.push(Entity::sensor(
+        "read_failures",
+        diagnostic()
+            .name(String::from("Consecutive read failures"))
+            .value_template(String::from("{{ value_json.consecutive_failures }}")),
+    ));
+    entities.push(Entity::sensor(
+        "last_updated",
+        base()
+            .name(String::from("Last updated"))
+            .device_class(String::from("timestamp"))
+            .value_template(args.timestamp_format.template("value_json.last_updated"))
+            .entity_category(String::from("diagnostic")),
+    ));
+    if sysfs::has_capacity_level() {
+        entities.push(Entity::sensor(
+            "capacity_level",
+            base()
+                .name(String::from("Capacity level"))
+                .device_class(String::from("enum"))
+                .options(CAPACITY_LEVELS)
+                .value_template(String::from(
+                    "{% set level = value_json.capacity_level | default('unknown') | lower %}\
+                     {{ level if level in ['critical', 'low', 'normal', 'high', 'full'] else None }}",
+                )),
+        ));
+    }
+    if sysfs::has_mains() || args.ac_only {
+        entities.push(Entity::sensor(
+            "charger_type",
+            base()
+                .name(String::from("Charger type"))
+                .value_template(String::from(
+                    "{{ value_json.charger_type | default('none') }}",
+                ))
+                .entity_category(String::from("diagnostic")),
+        ));
+    }
+    let input_limits = [
+        (
+            "input_current_limit",
+            "Charger current limit",
+            "current",
+            "A",
+        ),
+        (
+            "input_voltage_limit",
+            "Charger voltage limit",
+            "voltage",
+            "V",
+        ),
+    ];
+    for (key, name, device_class, unit) in input_limits {
+        if sysfs::has_input_limit(key) {
+            entities.push(Entity::sensor(
+                key,
+                base()
+                    .name(String::from(name))
+                    .device_class(String::from(device_class))
+                    .unit_of_measurement(String::from(unit))
+                    .value_template(format!("{{{{ value_json.{} | default(None) }}}}", key))
+                    .entity_category(String::from("diagnostic")),
+            ));
+        }
+    }
+    if args.platform {
+        entities.push(Entity::sensor(
+            "power_profile",
+            base()
+                .name(String::from("Power profile"))
+                .device_class(String::from("enum"))
+                .options(POWER_PROFILES)
+                .value_template(String::from(
+                    "{{ value_json.power_profile | default(None) }}",
+                )),
+        ));
+        entities.push(Entity {
+            key: "lid_closed",
+            comp: DiscoveryDevice::BinarySensor,
+            battery: None,
+            payload: base()
+                .name(String::from("Lid"))
+                .device_class(String::from("opening"))
+                .value_template(String::from(
+                    "{{ 'OFF' if value_json.lid_closed | default(false) else 'ON' }}",
+                )),
+        });
+    }
+    if sysfs::has_mains() || args.ac_only {
+        entities.push(Entity {
+            key: "ac_connected",
+            comp: DiscoveryDevice::BinarySensor,
+            battery: None,
+            payload: base()
+                .name(String::from("AC adapter"))
+                .device_class(String::from("plug"))
+                .value_template(String::from(
+                    "{% set ac = value_json.ac_connected | default(None) %}\
+                     {{ None if ac is none else ('ON' if ac else 'OFF') }}",
+                )),
+        });
+    }
+    if sysfs::has_charge_start_threshold() {
+        entities.push(Entity::sensor(
+            "charge_start_threshold",
+            base()
+                .name(String::from("Charge start threshold"))
+                .unit_of_measurement(String::from("%"))
+                .value_template(String::from("{{ value_json.charge_start_threshold }}"))
+                .entity_category(String::from("diagnostic")),
+        ));
+    }
+    if sysfs::has_charge_end_threshold() {
+        entities.push(Entity::sensor(
+            "charge_end_threshold_applied",
+            base()
+                .name(String::from("Charge end threshold"))
+                .unit_of_measurement(String::from("%"))
+                .value_template(String::from("{{ value_json.charge_end_threshold }}"))
+                .entity_category(String::from("diagnostic")),
+        ));
+    }
+    if args.allow_threshold_writes && sysfs::has_charge_end_threshold() {
+        entities.push(Entity {
+            key: "charge_end_threshold",
+            comp: DiscoveryDevice::Number,
+            battery: None,
+            payload: stateless()
+                .state_topic(state_topic.clone())
+                .name(String::from("Charge limit"))
+                .device_class(String::from("battery"))
+                .command_topic(threshold_command_topic(&args.topic))
+                .unit_of_measurement(String::from("%"))
+                .value_template(String::from("{{ value_json.charge_end_threshold }}"))
+                .entity_category(String::from("config")),
+        });
+    }
+    let behaviours = sysfs::charge_behaviours();
+    if !behaviours.is_empty() {
+        let options: Vec<&str> = behaviours.iter().map(String::as_str).collect();
+        let template = String::from("{{ value_json.charge_behaviour | default(None) }}");
+        entities.push(if args.control_charge_behaviour {
+            Entity {
+                key: "charge_behaviour",
+                comp: DiscoveryDevice::Select,
+                battery: None,
+                payload: stateless()
+                    .state_topic(state_topic.clone())
+                    .name(String::from("Charge behaviour"))
+                    .command_topic(behaviour_command_topic(&args.topic))
+                    .options(&options)
+                    .value_template(template)
+                    .entity_category(String::from("config")),
+            }
+        } else {
+            Entity::sensor(
+                "charge_behaviour",
+                base()
+                    .name(String::from("Charge behaviour"))
+                    .device_class(String::from("enum"))
+                    .options(&options)
+                    .value_template(template)
+                    .entity_category(String::from("diagnostic")),
+            )
+        });
+    }
+    entities.push(Entity {
+        key: "refresh",
+        comp: DiscoveryDevice::Button,
+        battery: None,
+        payload: stateless()
+            .name(String::from("Refresh"))
+            .command_topic(refresh_command_topic(&args.topic))
+            .entity_category(String::from("diagnostic")),
+    });
+    entities.push(Entity {
+        key: "paused",
+        comp: DiscoveryDevice::Switch,
+        battery: None,
+        payload: stateless()
+            .name(String::from("Pause publishing"))
+            .state_topic(paused_topic(&args.topic))
+            .command_topic(paused_command_topic(&args.topic))
+            .value_template(String::from("{{ value }}"))
+            .entity_category(String::from("config")),
+    });
+    entities.push(Entity {
+        key: "version",
+        comp: DiscoveryDevice::Update,
+        battery: None,
+        payload: stateless()
+            .name(String::from("Daemon"))
+            .state_topic(version_topic(&args.topic))
+            .entity_category(String::from("diagnostic")),
+    });
+    if args.ac_only {
+        entities.retain(|entity| AC_ONLY_ENTITIES.contains(&entity.key));
+        entities.push(Entity::sensor(
+            "boot_time",
+            base()
+                .name(String::from("Last boot"))
+                .device_class(String::from("timestamp"))
+                .value_template(
+                    args.timestamp_format
+                        .template("value_json.boot_time | default(None)"),
+                )
+                .entity_category(String::from("diagnostic")),
+        ));
+    }
+    for battery in host.batteries.clone() {
+        let topic = battery_state_topic(&args.topic, &battery);
+        for (key, payload) in sensors(&topic) {
+            entities.push(Entity {
+                battery: Some(battery.clone()),
+                ..Entity::sensor(key, payload)
+            });
+        }
+    }
+    for ups in &args.ups {
+        let reading = || base().state_topic(ups_state_topic(&args.topic, &ups.name));
+        let ups_sensors = [
+            (
+                "ups_charge",
+                reading()
+                    .name(String::from("UPS charge"))
+                    .device_class(String::from("battery"))
+                    .unit_of_measurement(String::from("%"))
+                    .value_template(String::from("{{ value_json.charge }}")),
+            ),
+            (
+                "ups_load",
+                reading()
+                    .name(String::from("UPS load"))
+                    .unit_of_measurement(String::from("%"))
+                    .value_template(String::from("{{ value_json.load }}")),
+            ),
+            (
+                "ups_runtime",
+                reading()
+                    .name(String::from("UPS runtime"))
+                    .device_class(String::from("duration"))
+                    .unit_of_measurement(String::from("s"))
+                    .value_template(String::from("{{ value_json.runtime }}")),
+            ),
+            (
+                "ups_status",
+                reading()
+                    .name(String::from("UPS status"))
+                    .value_template(String::from("{{ value_json.status }}")),
+            ),
+            (
+                "ups_input_voltage",
+                reading()
+                    .name(String::from("UPS input voltage"))
+                    .device_class(String::from("voltage"))
+                    .unit_of_measurement(String::from("V"))
+                    .value_template(String::from("{{ value_json.input_voltage }}"))
+                    .entity_category(String::from("diagnostic")),
+            ),
+        ];
+        for (key, payload) in ups_sensors {
+            entities.push(Entity {
+                battery: Some(sanitize_object_id(&ups.name)),
+                ..Entity::sensor(key, payload)
+            });
+        }
+    }
+    entities
+}
+
+/// Every discovery config the daemon publishes for this host.
+fn discovery_configs(args: &Args, host: &Host) -> Vec<Discovery> {
+    let topic_base = || discovery_topic_builder(args, host);
+    let filter = sensor_filter(args);
+    entities(args, host)
+        .into_iter()
+        .filter(|entity| filter.enabled(entity.key))
+        .map(|mut entity| {
+            if let Some((_, icon)) = args.icons.iter().find(|(key, _)| key == entity.key) {
+                entity.payload = entity.payload.icon(icon.clone());
+            }
+            let measured = matches!(
+                entity.comp,
+                DiscoveryDevice::Sensor | DiscoveryDevice::BinarySensor
+            );
+            if args.force_update && measured {
+                entity.payload = entity.payload.force_update(true);
+            }
+            if entity.key == PRIMARY_SENSOR && entity.battery.is_none() {
+                entity.payload = entity
+                    .payload
+                    .json_attributes_topic(attributes_topic(&args.topic));
+            }
+            let override_key = match &entity.battery {
+                Some(battery) => format!("{}_{}", entity.key, battery),
+                None => entity.key.to_string(),
+            };
+            if let Some(sensor) = args.settings.sensors.get(&override_key) {
+                entity.payload = entity.payload.apply_override(sensor);
+            }
+            entity_discovery(topic_base(), entity)
+        })
+        .collect()
+}
+
+/// Folds the per-entity configs into a single device-based discovery config
+/// published to `<prefix>/device/[<node_id>/]<hostname>/config`.
+fn device_discovery(args: &Args, host: &Host, discoveries: Vec<Discovery>) -> Message {
+    let topic = discovery_topic_builder(args, host)
+        .comp(DiscoveryDevice::Device)
+        .build();
+    let mut device = host.device.clone();
+    let mut components = BTreeMap::new();
+    for discovery in discoveries {
+        let mut payload = discovery.payload;
+        if let Some(shared) = payload.device.take() {
+            device = shared;
+        }
+        payload.origin = None;
+        let component = DeviceComponent {
+            platform: discovery.topic.comp.to_string(),
+            payload,
+        };
+        components.insert(discovery.topic.object_id, component);
+    }
+    let payload = DeviceDiscoveryPayload {
+        device,
+        origin: Origin::new(),
+        components,
+    };
+    MessageBuilder::new()
+        .topic(topic.to_string())
+        .payload(serde_json::to_string(&payload).unwrap_or_default())
+        .retain(true)
+        .build()
+}
+
+/// The retained messages that announce this host to Home Assistant.
+fn discovery_messages(args: &Args, host: &Host) -> Vec<Message> {
+    let discoveries = discovery_configs(args, host);
+    let mut messages: Vec<Message> = if args.device_discovery {
+        vec![device_discovery(args, host, discoveries)]
+    } else {
+        discoveries
+            .into_iter()
+            .map(|discovery| MessageBuilder::from(discovery).retain(true).build())
+            .collect()
+    };
+    if args.abbreviate {
+        for message in &mut messages {
+            message.payload = abbreviate::abbreviate_payload(mem::take(&mut message.payload));
+        }
+    }
+    messages
+}
+
+/// Discovery config for a peripheral's charge, published when the peripheral
+/// first shows up as they come and go at runtime. Sources read less often than
+/// the heartbeat stretch `expire_after` to twice their `interval`.
+fn peripheral_discovery(
+    args: &Args,
+    host: &Host,
+    peripheral: &Peripheral,
+    interval: Duration,
+) -> Message {
+    let topic = discovery_topic_builder(args, host).comp(DiscoveryDevice::Sensor);
+    let object_id = format!("{}_{}", topic.object_id, peripheral.id);
+    let mut payload = DiscoveryPayloadBuilder::new()
+        .name(format!("{} battery", peripheral.name))
+        .device_class(String::from("battery"))
+        .state_topic(peripheral_state_topic(&args.topic, &peripheral.id))
+        .unit_of_measurement(String::from("%"))
+        .value_template(String::from("{{ value_json.percentage }}"))
+        .json_attributes_topic(peripheral_state_topic(&args.topic, &peripheral.id))
+        .availability_topic(availability_topic(&args.topic))
+        .expire_after(expire_after(args).max((interval * 2).as_secs()))
+        .device(host.device.clone())
+        .origin(Origin::new())
+        .unique_id(object_id.clone())
+        .build()
+        .to_string();
+    if args.abbreviate {
+        payload = abbreviate::abbreviate_payload(payload);
+    }
+    MessageBuilder::new()
+        .topic(topic.object_id(object_id).build().to_string())
+        .payload(payload)
+        .retain(true)
+        .build()
+}
+
+/// Every peripheral the enabled sources currently report.
+async fn read_peripherals(args: &Args) -> Vec<Peripheral> {
+    let mut peripherals = Vec::new();
+    if args.bluetooth {
+        #[cfg(target_os = "linux")]
+        match bluez::peripherals().await {
+            Ok(found) => peripherals.extend(found),
+            Err(e) => warn!("{:#}", e),
+        }
+    }
+    if args.peripherals {
+        #[cfg(target_os = "linux")]
+        match upower::peripherals().await {
+            Ok(found) => peripherals.extend(found),
+            Err(e) => warn!("{:#}", e),
+        }
+    }
+    if args.hid {
+        peripherals.extend(sysfs::hid_peripherals());
+    }
+    let filter = &args.settings.peripherals;
+    peripherals.retain(|peripheral| filter.allows(&peripheral.id, &peripheral.name));
+    peripherals
+}
+
+/// Where a peripheral task gets its readings from.
+enum PeripheralSource {
+    /// Devices attached to the host, from BlueZ, UPower and sysfs.
+    Attached,
+    External(ExternalSource),
+}
+
+impl PeripheralSource {
+    async fn read(&self, args: &Args) -> Vec<Peripheral> {
+        let mut peripherals = match self {
+            PeripheralSource::Attached => read_peripherals(args).await,
+            PeripheralSource::External(source) => match external::read(source).await {
+                Ok(peripheral) => vec![peripheral],
+                Err(e) => {
+                    warn!("{:#}", e);
+                    Vec::new()
+                }
+            },
+        };
+        if let Some(decimals) = args.precision {
+            for peripheral in &mut peripherals {
+                peripheral.state.percentage = round_to(peripheral.state.percentage, decimals);
+            }
+        }
+        peripherals
+    }
+
+    fn interval(&self, args: &Args) -> Duration {
+        match self {
+            PeripheralSource::Attached => poll_interval(args),
+            PeripheralSource::External(source) => Duration::from_secs(source.interval),
+        }
+    }
+}
+
+/// Publishes the charge of every peripheral `source` reports, announcing each
+/// one to Home Assistant the first time it shows up.
+async fn publish_peripherals(
+    args: Args,
+    source: PeripheralSource,
+    tx: mpsc::Sender<Message>,
+    paused: Arc<AtomicBool>,
+) {
+    let host = Host::read(&args).await;
+    let mut announced = BTreeSet::new();
+    let mut published: BTreeMap<String, (PeripheralState, time::Instant)> = BTreeMap::new();
+    loop {
+        for peripheral in source.read(&args).await {
+            let new = !args.no_discovery && announced.insert(peripheral.id.clone());
+            let discovery =
+                || peripheral_discovery(&args, &host, &peripheral, source.interval(&args));
+            if new && tx.send(discovery()).await.is_err() {
+                error!("receiver dropped")
+            }
+            let due = match published.get(&peripheral.id) {
+                Some((state, at)) => *state != peripheral.state || at.elapsed() >= heartbeat(&args),
+                None => true,
+            };
+            if due && !paused.load(Ordering::Relaxed) {
+                let message = MessageBuilder::new()
+                    .payload(serde_json::to_string(&peripheral.state).unwrap_or_default())
+                    .topic(peripheral_state_topic(&args.topic, &peripheral.id))
+                    .retain(true)
+                    .build();
+                if tx.send(message).await.is_err() {
+                    error!("receiver dropped")
+                }
+                published.insert(peripheral.id, (peripheral.state, time::Instant::now()));
+            }
+        }
+        time::sleep(source.interval(&args)).await;
+    }
+}
+
+/// A copy of the discovery messages to publish.
+fn current(discoveries: &Mutex<Vec<Message>>) -> Vec<Message> {
+    discoveries
+        .lock()
+        .map(|discoveries| discoveries.clone())
+        .unwrap_or_default()
+}
+
+/// Where messages go: the broker, or stdout with `--dry-run`.
+#[derive(Clone)]
+enum Publisher {
+    Broker(AsyncClient),
+    DryRun,
+}
+
+async fn publish_all(publisher: Publisher, messages: Vec<Message>) {
+    for message in messages {
+        mqtt_send(publisher.clone(), message).await;
+    }
+}
+
+/// Prints `message` with its topic, for `--dry-run`.
+fn print_message(message: &Message) {
+    let retained = if message.retain { " (retained)" } else { "" };
+    println!("{}{} {}", message.topic, retained, message.payload);
+}
+
+async fn mqtt_send(publisher: Publisher, message: Message) {
+    let client = match publisher {
+        Publisher::Broker(client) => client,
+        Publisher::DryRun => {
+            print_message(&message);
+            return;
+        }
+    };
+    match client
+        .publish(
+            message.topic.clone(),
+            QoS::AtLeastOnce,
+            message.retain,
+            message.payload.clone(),
+        )
+        .await
+    {
+        Err(e) => error!(topic = %message.topic, error = ?e, "queueing the message failed"),
+        _ => debug!(
+            topic = %message.topic,
+            bytes = message.payload.len(),
+            retain = message.retain,
+            "sending {}",
+            message.payload
+        ),
+    }
+}
+
+/// The battery crate's readings in SI base units (J, W, V, K, s), before any
+/// of the fallbacks `get_charge_info` applies.
+fn raw_charge_info() -> Result<serde_json::Value> {
+    let manager = battery::Manager::new()?;
+    let mut raw = Vec::new();
+    for dev in manager.batteries()? {
+        let battery = dev?;
+        raw.push(serde_json::json!({
+            "state": battery.state().to_string(),
+            "state_of_charge": battery.state_of_charge().value,
+            "energy": battery.energy().value,
+            "energy_full": battery.energy_full().value,
+            "energy_full_design": battery.energy_full_design().value,
+            "energy_rate": battery.energy_rate().value,
+            "voltage": battery.voltage().value,
+            "temperature": battery.temperature().map(|t| t.value),
+            "cycle_count": battery.cycle_count(),
+            "time_to_full": battery.time_to_full().map(|t| t.value),
+            "time_to_empty": battery.time_to_empty().map(|t| t.value),
+            "vendor": battery.vendor(),
+            "model": battery.model(),
+            "serial_number": battery.serial_number(),
+            "technology": battery.technology().to_string(),
+        }));
+    }
+    Ok(serde_json::Value::Array(raw))
+}
+
+fn get_charge_info(options: &ReadOptions) -> Result<(ChargeInfo, BatteryAttributes)> {
+    let manager = battery::Manager::new()?;
+    let mut batteries = BTreeMap::new();
+    let mut claimed = Vec::new();
+    let mut attributes = BTreeMap::new();
+    for (index, dev) in manager.batteries()?.enumerate() {
+        let battery = dev?;
+        let dir = sysfs::find_battery(battery.serial_number(), battery.model(), &claimed);
+        let name = dir
+            .as_deref()
+            .and_then(sysfs::name)
+            .unwrap_or_else(|| format!("bat{}", index));
+        if !options.selects(&name) {
+            claimed.extend(dir);
+            continue;
+        }
+        // Some firmware only reports charge, which the battery crate then
+        // turns into zero energy.
+        let sysfs_energy = |name| dir.as_deref().and_then(|dir| sysfs::energy(dir, name));
+        let energy_full = Some(battery.energy_full().get::<watt_hour>())
+            .filter(|energy| *energy > 0.0)
+            .or_else(|| sysfs_energy("full"))
+            .unwrap_or_default();
+        let energy_full_design = Some(battery.energy_full_design().get::<watt_hour>())
+            .filter(|energy| *energy > 0.0)
+            .or_else(|| sysfs_energy("full_design"))
+            .unwrap_or_default();
+        // Some firmware reports 0 cycles when it does not count them at all.
+        let cycle_count = battery
+            .cycle_count()
+            .filter(|cycles| *cycles > 0)
+            .or_else(|| dir.as_deref().and_then(sysfs::cycle_count))
+            .or(battery.cycle_count());
+        let info = ChargeInfo {
+            percentage: battery.state_of_charge().get::<percent>(),
+            state: battery.state(),
+            energy_rate: battery.energy_rate().get::<watt>(),
+            energy_now: battery.energy().get::<watt_hour>(),
+            energy_full,
+            energy_full_design,
+            voltage: battery.voltage().get::<volt>(),
+            health: (energy_full_design > 0.0).then(|| energy_full / energy_full_design * 100.0),
+            cycle_count,
+            time_to_target: None,
+            energy_rate_smoothed: None,
+            time_remaining: None,
+            time_remaining_confidence: None,
+            needs_calibration: None,
+            energy_full_spread: None,
+            ac_connected: None,
+            charger_type: None,
+            input_current_limit: None,
+            input_voltage_limit: None,
+            power_profile: None,
+            lid_closed: None,
+            boot_time: None,
+            charge_start_threshold: dir.as_deref().and_then(sysfs::charge_start_threshold),
+            charge_end_threshold: dir.as_deref().and_then(sysfs::charge_end_threshold),
+            capacity_level: dir
+                .as_deref()
+                .and_then(|dir| sysfs::read_attr(dir, "capacity_level")),
+            charge_behaviour: dir.as_deref().and_then(sysfs::charge_behaviour),
+            current: None,
+            power: None,
+            condition: None,
+            vendor: battery.vendor().map(String::from),
+            model: battery.model().map(String::from),
+            serial_number: battery.serial_number().map(String::from),
+            technology: Some(battery.technology().to_string()),
+            batteries: BTreeMap::new(),
+        };
+        attributes.entry(name.clone()).or_insert(BatteryAttributes {
+            vendor: battery.vendor().map(String::from),
+            model: battery.model().map(String::from),
+            serial_number: battery.serial_number().map(String::from),
+            technology: Some(battery.technology().to_string()),
+            cycle_count,
+            temperature: battery.temperature().map(|t| t.get::<degree_celsius>()),
+            charge_start_threshold: info.charge_start_threshold,
+            charge_end_threshold: info.charge_end_threshold,
+            backend: None,
+        });
+        claimed.extend(dir);
+        batteries.insert(name, info);
+    }
+    if batteries.is_empty() {
+        bail!("no battery found");
+    }
+    let mut info = ChargeInfo::combine(&batteries.values().collect::<Vec<_>>(), options.aggregate);
+    info.ac_connected = sysfs::ac_online();
+    info.charger_type = sysfs::charger_type();
+    info.input_current_limit = sysfs::input_limit("input_current_limit");
+    info.input_voltage_limit = sysfs::input_limit("input_voltage_limit");
+    info.batteries = batteries;
+    Ok((info, attributes.into_values().next().unwrap_or_default()))
 }
 
-impl DiscoveryTopicBuilder {
-    fn new() -> DiscoveryTopicBuilder {
-        if let Ok(hostname) = gethostname().into_string() {
-            DiscoveryTopicBuilder {
-                discovery_prefix: String::from("homeassistant"),
-                comp: DiscoveryDevice::NoneType,
-                node_id: NodeID::Empty,
-                object_id: hostname,
-            }
-        } else {
-            DiscoveryTopicBuilder {
-                discovery_prefix: String::from("homeassistant"),
-                comp: DiscoveryDevice::NoneType,
-                node_id: NodeID::Empty,
-                object_id: String::from(""),
-            }
-        }
+/// Connection options for the broker, logged in when a user name is given.
+/// The MQTT client id of the daemon: the topic, plus the profile so profiles
+/// sharing a topic do not keep disconnecting each other. The subcommands add
+/// their own suffix.
+fn client_id(args: &Args) -> String {
+    match &args.profile {
+        Some(profile) => format!("{}-{}", args.topic, profile),
+        None => args.topic.clone(),
     }
-    fn build(self) -> DiscoveryTopic {
-        DiscoveryTopic {
-            discovery_prefix: self.discovery_prefix,
-            comp: self.comp,
-            node_id: self.node_id,
-            object_id: self.object_id,
-        }
+}
+
+fn mqtt_options(args: &Args, client_id: String) -> MqttOptions {
+    let mut options = MqttOptions::new(client_id, &args.hostname, args.port);
+    options.set_keep_alive(Duration::from_secs(10));
+    if let Some(username) = &args.username {
+        let password = args
+            .password
+            .as_deref()
+            .map(String::as_str)
+            .unwrap_or_default();
+        options.set_credentials(username, password);
     }
-    fn comp(mut self, comp: DiscoveryDevice) -> DiscoveryTopicBuilder {
-        self.comp = comp;
-        self
+    options
+}
+
+/// Where readings come from: the backend, or just the AC with `--ac-only`.
+fn power_source(args: &Args) -> Box<dyn source::PowerSource> {
+    if args.ac_only {
+        Box::new(source::AcOnly)
+    } else {
+        args.backend.source()
     }
 }
 
-struct Discovery {
-    topic: DiscoveryTopic,
-    payload: DiscoveryPayload,
+/// The sensors `--include-sensor` and `--exclude-sensor` leave enabled.
+fn sensor_filter(args: &Args) -> SensorFilter {
+    SensorFilter::new(&args.include_sensors, &args.exclude_sensors)
 }
 
-#[derive(PartialEq)]
-enum DiscoveryDevice {
-    BinarySensor,
-    Sensor,
-    NoneType,
+/// How often the battery is sampled, with `--interval`.
+fn poll_interval(args: &Args) -> Duration {
+    Duration::from_secs(args.interval)
 }
 
-impl fmt::Display for DiscoveryDevice {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match *self {
-            Self::BinarySensor => return write!(f, "binary_sensor"),
-            Self::Sensor => return write!(f, "sensor"),
-            _ => return write!(f, "none"),
-        };
+/// Unchanged state is re-published this often so consumers can tell a steady
+/// battery from a daemon that went away.
+fn heartbeat(args: &Args) -> Duration {
+    Duration::from_secs(args.heartbeat)
+}
+
+/// Time until the wall clock next reaches a multiple of `interval` since the
+/// epoch, e.g. the next full minute for 60s, so hosts sample at the same time.
+fn until_aligned(interval: Duration) -> Duration {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let interval_ms = interval.as_millis().max(1);
+    let into_interval = since_epoch.as_millis() % interval_ms;
+    Duration::from_millis((interval_ms - into_interval) as u64)
+}
+
+fn read_options(args: &Args) -> ReadOptions {
+    ReadOptions {
+        aggregate: args.settings.aggregate,
+        batteries: args.batteries.clone(),
     }
 }
 
-#[derive(PartialEq)]
-enum NodeID {
-    Empty,
-    Is(String),
+/// The command line, with every option accepted after a subcommand too, e.g.
+/// `run --topic home/laptop` as well as `--topic home/laptop run`.
+fn cli() -> clap::Command {
+    let command = Args::command();
+    let ids: Vec<_> = command
+        .get_arguments()
+        .filter(|arg| !arg.is_positional())
+        .map(|arg| arg.get_id().clone())
+        .collect();
+    ids.into_iter().fold(command, |command, id| {
+        command.mut_arg(id, |arg| arg.global(true))
+    })
 }
 
-#[derive(PartialEq)]
-struct Message {
-    topic: String,
-    payload: String,
-    retain: bool,
+/// Loads the options, once per profile of the config files, and reads the
+/// broker passwords they point to.
+fn load_args(matches: &ArgMatches) -> Result<Vec<Args>> {
+    let base = load_options(matches)?;
+    let profiles = &base.settings.profiles;
+    let names: Vec<String> = match &base.profile {
+        Some(name) => vec![name.clone()],
+        None => profiles.keys().cloned().collect(),
+    };
+    let mut all = if names.is_empty() {
+        vec![base]
+    } else {
+        names
+            .iter()
+            .map(|name| {
+                let mut args = with_settings(matches, base.settings.profile(name)?)?;
+                args.profile = Some(name.clone());
+                Ok(args)
+            })
+            .collect::<Result<_>>()?
+    };
+    for args in &mut all {
+        args.password = credentials::password(args)?;
+    }
+    Ok(all)
 }
 
-struct MessageBuilder {
-    topic: String,
-    payload: String,
-    retain: bool,
+/// Combines the parsed command line and environment with the options both
+/// leave out from the config files.
+fn load_options(matches: &ArgMatches) -> Result<Args> {
+    let paths = config::paths(matches.get_one::<PathBuf>("config").map(PathBuf::as_path));
+    if paths.is_empty() {
+        return Ok(Args::from_arg_matches(matches)?);
+    }
+    with_settings(matches, Config::load(&paths)?)
 }
 
-impl MessageBuilder {
-    fn new() -> MessageBuilder {
-        MessageBuilder {
-            topic: String::from(""),
-            payload: String::from(""),
-            retain: false,
-        }
+/// Parses the command line again with the options of `settings` it leaves
+/// out.
+fn with_settings(matches: &ArgMatches, settings: Config) -> Result<Args> {
+    let given = |id: &str| {
+        matches!(
+            matches.value_source(id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        )
+    };
+    settings.check_locks(&Args::command(), given)?;
+    let files = config::describe(&settings.files);
+    let arguments = settings
+        .arguments(&Args::command(), given)
+        .with_context(|| format!("in {}", files))?;
+    // Before the command line's own arguments, which may end in a subcommand.
+    let mut argv: Vec<OsString> = env::args_os().collect();
+    argv.splice(1..1, arguments.into_iter().map(OsString::from));
+    let mut args = cli()
+        .try_get_matches_from(argv)
+        .and_then(|matches| Args::from_arg_matches(&matches))
+        .with_context(|| format!("in {}", files))?;
+    args.settings = settings;
+    Ok(args)
+}
+
+/// Loads the options again for SIGHUP. Those the running daemon cannot
+/// change keep their current value until a restart:
+///
+/// - `--topic`, which the broker's last will is published to, and the broker
+///   options `--hostname`, `--port`, `--username` and the password, all used
+///   when connecting;
+/// - `--backend` and `--ac-only`, whose source may be watching the battery;
+/// - `--ups`, `--bluetooth`, `--peripherals`, `--hid` and the `[[external]]`
+///   commands, which each have a task of their own;
+/// - `--rediscover-interval` and `--dry-run`;
+/// - the logging options, `-v`, `-q`, `--log-level` and `--log-format`.
+///
+/// Everything else, like the intervals, thresholds, sensors and discovery
+/// settings, takes effect right away.
+fn reload_args(current: &Args) -> Result<Args> {
+    let mut args = load_args(&cli().try_get_matches()?)?
+        .into_iter()
+        .find(|args| args.profile == current.profile)
+        .with_context(|| format!("profile {:?} is gone", current.profile))?;
+    let changed: Vec<&str> = [
+        ("topic", args.topic != current.topic),
+        ("hostname", args.hostname != current.hostname),
+        ("port", args.port != current.port),
+        ("username", args.username != current.username),
+        (
+            "password",
+            args.password.as_deref() != current.password.as_deref(),
+        ),
+        ("backend", args.backend != current.backend),
+        ("ac-only", args.ac_only != current.ac_only),
+        ("ups", args.ups != current.ups),
+        ("bluetooth", args.bluetooth != current.bluetooth),
+        ("peripherals", args.peripherals != current.peripherals),
+        ("hid", args.hid != current.hid),
+        (
+            "external",
+            args.settings.external != current.settings.external,
+        ),
+        (
+            "rediscover-interval",
+            args.rediscover_interval != current.rediscover_interval,
+        ),
+        ("dry-run", args.dry_run != current.dry_run),
+    ]
+    .into_iter()
+    .filter_map(|(name, changed)| changed.then_some(name))
+    .collect();
+    if !changed.is_empty() {
+        warn!("--{} only change after a restart", changed.join(", --"));
     }
+    args.topic = current.topic.clone();
+    args.hostname = current.hostname.clone();
+    args.port = current.port;
+    args.username = current.username.clone();
+    args.password = current.password.clone();
+    args.backend = current.backend;
+    args.ac_only = current.ac_only;
+    args.ups = current.ups.clone();
+    args.bluetooth = current.bluetooth;
+    args.peripherals = current.peripherals;
+    args.hid = current.hid;
+    args.settings.external = current.settings.external.clone();
+    args.rediscover_interval = current.rediscover_interval;
+    args.dry_run = current.dry_run;
+    Ok(args)
+}
 
-    fn build(self) -> Message {
-        Message {
-            topic: self.topic,
-            payload: self.payload,
-            retain: self.retain,
-        }
+fn current_args(args: &Mutex<Args>) -> Args {
+    match args.lock() {
+        Ok(args) => args.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
     }
-    fn retain(mut self, retain: bool) -> MessageBuilder {
-        self.retain = retain;
-        self
+}
+
+/// The command topics besides refresh and pause that the options enable and
+/// the firmware supports.
+#[derive(Clone, Default, PartialEq)]
+struct CommandTopics {
+    threshold: Option<String>,
+    charge: Option<String>,
+    behaviour: Option<String>,
+}
+
+impl CommandTopics {
+    fn new(args: &Args) -> CommandTopics {
+        let thresholds = args.allow_threshold_writes && sysfs::has_charge_end_threshold();
+        let behaviours = args.control_charge_behaviour && !sysfs::charge_behaviours().is_empty();
+        CommandTopics {
+            threshold: thresholds.then(|| threshold_command_topic(&args.topic)),
+            charge: thresholds.then(|| charge_command_topic(&args.topic)),
+            behaviour: behaviours.then(|| behaviour_command_topic(&args.topic)),
+        }
     }
 
-    fn topic(mut self, topic: String) -> MessageBuilder {
-        self.topic = topic;
-        self
+    fn iter(&self) -> impl Iterator<Item = &String> {
+        self.threshold
+            .iter()
+            .chain(&self.charge)
+            .chain(&self.behaviour)
     }
+}
 
-    fn payload(mut self, payload: String) -> MessageBuilder {
-        self.payload = payload;
-        self
+fn current_commands(commands: &Mutex<CommandTopics>) -> CommandTopics {
+    match commands.lock() {
+        Ok(commands) => commands.clone(),
+        Err(poisoned) => poisoned.into_inner().clone(),
     }
 }
 
-impl From<Discovery> for MessageBuilder {
-    fn from(value: Discovery) -> MessageBuilder {
-        MessageBuilder {
-            topic: value.topic.to_string(),
-            payload: value.payload.to_string(),
-            retain: false,
+/// Subscribes to the command topics `args` enables and unsubscribes from those
+/// it no longer does.
+async fn resubscribe(publisher: &Publisher, commands: &Mutex<CommandTopics>, args: &Args) {
+    let new = CommandTopics::new(args);
+    let old = current_commands(commands);
+    if let Ok(mut commands) = commands.lock() {
+        *commands = new.clone();
+    }
+    let Publisher::Broker(client) = publisher else {
+        return;
+    };
+    for topic in new
+        .iter()
+        .filter(|topic| !old.iter().any(|old| old == *topic))
+    {
+        if let Err(e) = client.subscribe(topic, QoS::AtLeastOnce).await {
+            error!(error = ?e, "queueing the message failed");
+        }
+    }
+    for topic in old
+        .iter()
+        .filter(|topic| !new.iter().any(|new| new == *topic))
+    {
+        if let Err(e) = client.unsubscribe(topic).await {
+            error!(error = ?e, "queueing the message failed");
         }
     }
 }
 
-async fn home_assistant_discovery(
-    client: AsyncClient,
-    topic: DiscoveryTopic,
-    payload: DiscoveryPayload,
+/// Reloads the options on every SIGHUP, republishing the discovery configs
+/// when they change and clearing those of entities that went away, and
+/// following the command topics they enable.
+#[cfg(unix)]
+async fn reload_on_hangup(
+    settings: Arc<Mutex<Args>>,
+    discoveries: Arc<Mutex<Vec<Message>>>,
+    commands: Arc<Mutex<CommandTopics>>,
+    publisher: Publisher,
+    refresh: Arc<Notify>,
+    reloaded: Arc<Notify>,
 ) {
-    let discovery = Discovery { topic, payload };
-    let message: Message = MessageBuilder::from(discovery).retain(true).build();
-    mqtt_send(client, message).await;
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!(error = %e, "cannot reload on SIGHUP");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        let args = match reload_args(&current_args(&settings)) {
+            Ok(args) => args,
+            Err(e) => {
+                warn!("reloading the options failed, keeping them: {:#}", e);
+                continue;
+            }
+        };
+        resubscribe(&publisher, &commands, &args).await;
+        let messages = if args.no_discovery {
+            Vec::new()
+        } else {
+            discovery_messages(&args, &Host::read(&args).await)
+        };
+        let previous = current(&discoveries);
+        if messages != previous {
+            let gone = previous
+                .iter()
+                .filter(|old| !messages.iter().any(|new| new.topic == old.topic))
+                .map(|old| {
+                    MessageBuilder::new()
+                        .topic(old.topic.clone())
+                        .payload(String::new())
+                        .retain(true)
+                        .build()
+                })
+                .collect();
+            publish_all(publisher.clone(), gone).await;
+            publish_all(publisher.clone(), messages.clone()).await;
+            if let Ok(mut discoveries) = discoveries.lock() {
+                *discoveries = messages;
+            }
+        }
+        if let Ok(mut settings) = settings.lock() {
+            *settings = args;
+        }
+        info!("reloaded the options");
+        refresh.notify_one();
+        reloaded.notify_one();
+    }
 }
 
-async fn mqtt_send(client: AsyncClient, message: Message) {
-    match client
-        .publish(
-            message.topic,
-            QoS::AtLeastOnce,
-            message.retain,
-            message.payload.clone(),
-        )
-        .await
-    {
-        Err(e) => println!("Client error: {:?}", e),
-        _ => println!("sending {}", &message.payload),
+/// Runs a subcommand other than `run` and `init-config`, or
+/// `--print-discovery`, returning the exit status.
+async fn run_command(args: &Args) -> i32 {
+    if args.print_discovery {
+        verify::print(args).await;
+        return 0;
+    }
+    let passed = match &args.command {
+        Some(Command::Check) => check::run(args),
+        Some(Command::Verify) => verify::run(args).await,
+        Some(Command::CheckDiscovery) => verify::check(args).await,
+        Some(Command::RemoveDiscovery) => remove::run(args).await,
+        Some(Command::Discover) => once::discover(args).await,
+        Some(Command::Once { discovery }) => return once::run(args, *discovery).await,
+        Some(Command::InitConfig { .. } | Command::Run) | None => true,
+    };
+    if passed {
+        0
+    } else {
+        1
     }
 }
 
-fn get_charge_info() -> Result<ChargeInfo> {
-    let manager = battery::Manager::new()?;
-    let mut percentage = 0.0;
-    let mut state = State::Unknown;
-    for (_, dev) in manager.batteries()?.enumerate() {
-        let battery = dev?;
-        percentage = battery.state_of_charge().get::<percent>();
-        state = battery.state();
+/// Tags the log lines of a profile with its name.
+fn profile_span(args: &Args) -> tracing::Span {
+    match &args.profile {
+        Some(name) => tracing::info_span!("profile", name = %name),
+        None => tracing::Span::none(),
     }
-    let info = ChargeInfo { percentage, state };
-    Ok(info)
 }
 
 #[tokio::main]
 async fn main() {
-    let args = Args::parse();
-    let port = args.port;
-    let hostname = args.hostname;
+    let profiles = match load_args(&cli().get_matches()) {
+        Ok(profiles) => profiles,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(2);
+        }
+    };
+    // One log for all profiles, so their logging options are the same.
+    let first = &profiles[0];
+    logging::init(
+        first.verbose,
+        first.quiet,
+        first.log_level,
+        first.log_format,
+    );
+    match &first.command {
+        Some(Command::InitConfig { stdout, force }) => {
+            std::process::exit(if init_config::run(*stdout, *force) {
+                0
+            } else {
+                1
+            });
+        }
+        Some(Command::Run) | None if !first.print_discovery => {}
+        _ => {
+            let mut status = 0;
+            for args in &profiles {
+                if let (Some(name), true) = (&args.profile, profiles.len() > 1) {
+                    println!("[{}]", name);
+                }
+                status = status.max(run_command(args).instrument(profile_span(args)).await);
+            }
+            std::process::exit(status);
+        }
+    }
+    let runs: Vec<_> = profiles
+        .into_iter()
+        .map(|args| {
+            let span = profile_span(&args);
+            task::spawn(run(args).instrument(span))
+        })
+        .collect();
+    for run in runs {
+        if let Err(e) = run.await {
+            error!(error = %e, "a profile stopped");
+        }
+    }
+}
+
+/// Monitors the battery and publishes it with `args` until stopped.
+async fn run(args: Args) {
+    let host = Host::read(&args).await;
+    // Replaced when a battery shows up at runtime, e.g. a dock's.
+    let discoveries = Arc::new(Mutex::new(if args.no_discovery {
+        Vec::new()
+    } else {
+        discovery_messages(&args, &host)
+    }));
+    let peripheral_args = args.clone();
+    // What the tasks read, replaced on SIGHUP.
+    let settings = Arc::new(Mutex::new(args.clone()));
+    let commands = Arc::new(Mutex::new(CommandTopics::new(&args)));
+    let interval = poll_interval(&args);
+    let source = power_source(&args);
+    let mut options = mqtt_options(&args, client_id(&args));
     let topic = args.topic;
+    let rediscover_interval = args.rediscover_interval;
+    let dry_run = args.dry_run;
+    let backend = args.backend.name();
+    let ac_only = args.ac_only;
     let state_topic = format!("{}/state", topic);
+    let availability_topic = availability_topic(&topic);
+    let attributes_topic = attributes_topic(&topic);
+    let refresh_topic = refresh_command_topic(&topic);
+    let paused_topic = paused_topic(&topic);
+    let paused_command_topic = paused_command_topic(&topic);
+    // Set from Home Assistant to stop publishing the state and attributes.
+    let paused = Arc::new(AtomicBool::new(false));
+    // Makes the sampler read and publish right away, e.g. after a setting was
+    // changed.
+    let refresh = Arc::new(Notify::new());
+    // Makes the release check run again with the reloaded options.
+    let reloaded = Arc::new(Notify::new());
 
     let (tx, mut rx) = mpsc::channel(mem::size_of::<Message>());
 
-    let mut options = MqttOptions::new(&topic, &hostname, port);
-    options.set_keep_alive(Duration::from_secs(10));
+    options.set_last_will(LastWill::new(
+        &availability_topic,
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
     let (client, mut eventloop) = AsyncClient::new(options, 10);
+    let availability_client = client.clone();
+    let publisher = if dry_run {
+        Publisher::DryRun
+    } else {
+        Publisher::Broker(client.clone())
+    };
 
-    let discovery_topic: DiscoveryTopic = DiscoveryTopicBuilder::new()
-        .comp(DiscoveryDevice::Sensor)
-        .build();
-    let discovery_payload = DiscoveryPayload::new(
-        discovery_topic.object_id.clone(),
-        DiscoveryDevice::Sensor.to_string(),
-        state_topic.clone(),
-        String::from("%"),
-        String::from("{{ value_json.percentage }}"),
-    );
-    home_assistant_discovery(client.clone(), discovery_topic, discovery_payload).await;
+    if let Some(hours) = rediscover_interval {
+        let publisher = publisher.clone();
+        let discoveries = discoveries.clone();
+        task::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(hours * 3600));
+            // The first tick completes immediately; connecting already publishes.
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                publish_all(publisher.clone(), current(&discoveries)).await;
+            }
+        });
+    }
+
+    if peripheral_args.bluetooth || peripheral_args.peripherals || peripheral_args.hid {
+        task::spawn(publish_peripherals(
+            peripheral_args.clone(),
+            PeripheralSource::Attached,
+            tx.clone(),
+            paused.clone(),
+        ));
+    }
+    for external in &peripheral_args.settings.external {
+        task::spawn(publish_peripherals(
+            peripheral_args.clone(),
+            PeripheralSource::External(external.clone()),
+            tx.clone(),
+            paused.clone(),
+        ));
+    }
+
+    for ups in args.ups {
+        let tx = tx.clone();
+        let paused = paused.clone();
+        let settings = settings.clone();
+        let state_topic = ups_state_topic(&topic, &ups.name);
+        task::spawn(async move {
+            let mut prev_info = None;
+            let mut last_publish = time::Instant::now();
+            loop {
+                let args = current_args(&settings);
+                match nut::read(&ups).await {
+                    Ok(mut info) => {
+                        if let Some(decimals) = args.precision {
+                            info.round(decimals);
+                        }
+                        let due = prev_info.as_ref() != Some(&info)
+                            || last_publish.elapsed() >= heartbeat(&args);
+                        if due && !paused.load(Ordering::Relaxed) {
+                            let message = MessageBuilder::new()
+                                .payload(sensor_filter(&args).json("ups_", &info))
+                                .topic(state_topic.clone())
+                                .retain(true)
+                                .build();
+                            if tx.send(message).await.is_err() {
+                                error!("receiver dropped")
+                            }
+                            prev_info = Some(info);
+                            last_publish = time::Instant::now();
+                        }
+                    }
+                    Err(e) => warn!("{:#}", e),
+                }
+                let interval = poll_interval(&args);
+                time::sleep(if args.align {
+                    until_aligned(interval)
+                } else {
+                    interval
+                })
+                .await;
+            }
+        });
+    }
 
+    let version_tx = tx.clone();
+    let version_topic = version_topic(&topic);
+    let version_settings = settings.clone();
+    let version_reloaded = reloaded.clone();
     task::spawn(async move {
-        let mut prev_info = ChargeInfo {
-            percentage: 0.0,
-            state: State::Unknown,
+        loop {
+            let release_url = current_args(&version_settings).release_url;
+            let state = match &release_url {
+                Some(url) => {
+                    let url = url.clone();
+                    match task::spawn_blocking(move || release::fetch(&url)).await {
+                        Ok(Ok(state)) => state,
+                        Ok(Err(e)) => {
+                            warn!("checking for a new release failed: {:#}", e);
+                            release::VersionState::installed()
+                        }
+                        Err(e) => {
+                            warn!(error = ?e, "checking for a new release failed");
+                            release::VersionState::installed()
+                        }
+                    }
+                }
+                None => release::VersionState::installed(),
+            };
+            let message = MessageBuilder::new()
+                .payload(serde_json::to_string(&state).unwrap_or_default())
+                .topic(version_topic.clone())
+                .retain(true)
+                .build();
+            if version_tx.send(message).await.is_err() {
+                error!("receiver dropped")
+            }
+            tokio::select! {
+                _ = time::sleep(RELEASE_CHECK_INTERVAL), if release_url.is_some() => (),
+                _ = version_reloaded.notified() => (),
+            }
+        }
+    });
+
+    // Sources that announce changes themselves are only polled for heartbeats,
+    // until they stop watching.
+    let watching = Arc::new(AtomicBool::new(false));
+    if let Some(watch) = source.watch(refresh.clone(), interval) {
+        watching.store(true, Ordering::Relaxed);
+        let watching = watching.clone();
+        let refresh = refresh.clone();
+        task::spawn(async move {
+            if let Err(e) = watch.await {
+                warn!(
+                    "watching for battery changes failed, polling instead: {:#}",
+                    e
+                );
+            }
+            watching.store(false, Ordering::Relaxed);
+            // Cuts short the wait for a heartbeat.
+            refresh.notify_one();
+        });
+    }
+
+    if sysfs::has_critical_alarm() {
+        let tx = tx.clone();
+        let paused = paused.clone();
+        let refresh = refresh.clone();
+        let alert_topic = alert_topic(&topic);
+        let settings = settings.clone();
+        task::spawn(async move {
+            let mut critical = BTreeSet::new();
+            loop {
+                let now = sysfs::critical_batteries();
+                for (battery, reason) in &now {
+                    if critical.contains(battery) || paused.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    let alert = serde_json::json!({
+                        "battery": battery,
+                        "reason": reason,
+                        "timestamp": Timestamp::now(current_args(&settings).timestamp_format),
+                    });
+                    let message = MessageBuilder::new()
+                        .payload(alert.to_string())
+                        .topic(alert_topic.clone())
+                        .build();
+                    if tx.send(message).await.is_err() {
+                        error!("receiver dropped")
+                    }
+                    // Publish the state right away rather than at the next poll.
+                    refresh.notify_one();
+                }
+                critical = now.into_iter().map(|(battery, _)| battery).collect();
+                time::sleep(ALARM_INTERVAL).await;
+            }
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    if acpid::available() {
+        let refresh = refresh.clone();
+        task::spawn(async move {
+            if let Err(e) = acpid::watch(refresh).await {
+                warn!("watching acpid events failed: {:#}", e);
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    task::spawn(reload_on_hangup(
+        settings.clone(),
+        discoveries.clone(),
+        commands.clone(),
+        publisher.clone(),
+        refresh.clone(),
+        reloaded,
+    ));
+
+    // Whether the last read found a battery; without one the entities are
+    // reported unavailable rather than as an empty battery.
+    let battery_present = Arc::new(AtomicBool::new(true));
+    let sampler_refresh = refresh.clone();
+    let sampler_paused = paused.clone();
+    let sampler_present = battery_present.clone();
+    let sampler_availability_topic = availability_topic.clone();
+    let sampler_discoveries = discoveries.clone();
+    let sampler_settings = settings.clone();
+    let sampler_batteries = host.batteries.clone();
+    task::spawn(async move {
+        let refresh = &*sampler_refresh;
+        // Sleeps until the next read, returning whether it must be published
+        // even if unchanged: when asked to refresh or on a scheduled run.
+        let wait = |args: &Args, tier: PollTier| {
+            let (sleep, scheduled) = match &args.schedule {
+                Some(schedule) => {
+                    let now = Local::now();
+                    let until = schedule
+                        .next(now)
+                        .and_then(|next| (next - now).to_std().ok())
+                        .unwrap_or(Duration::MAX);
+                    (until.min(heartbeat(args)), until <= heartbeat(args))
+                }
+                None => {
+                    let every = match tier.interval(args) {
+                        Some(every) => every,
+                        None if watching.load(Ordering::Relaxed) => heartbeat(args),
+                        None => poll_interval(args).min(heartbeat(args)),
+                    };
+                    let sleep = if args.align {
+                        until_aligned(every)
+                    } else {
+                        every
+                    };
+                    (sleep, false)
+                }
+            };
+            async move {
+                tokio::select! {
+                    _ = time::sleep(sleep) => scheduled,
+                    _ = refresh.notified() => true,
+                }
+            }
         };
+        let mut prev_info = ChargeInfo::unknown();
+        let mut failures = 0;
+        let mut reported: Option<(u32, Option<String>)> = None;
+        let mut read_backend: Option<String> = None;
+        let mut last_error: Option<(String, DateTime<Utc>)> = None;
+        // Batteries with their own entities.
+        let mut announced: BTreeSet<String> = sampler_batteries.into_iter().collect();
+        let mut prev_attributes = None;
+        let mut curve = ChargeCurve::new();
+        let mut rate_samples = args.rate_samples;
+        let mut rate_average = RateAverage::new(rate_samples);
+        let mut remaining = RemainingTime::new();
+        let mut calibration = Calibration::new();
+        // Charge and time the charge curve last learned from, kept apart from
+        // `prev_info` as that only moves on publish.
+        let mut last_learned: Option<(f32, time::Instant)> = None;
+        let mut last_publish = time::Instant::now();
+        let mut forced = false;
+        let mut tier = PollTier::Normal;
+        let mut schedule = PublishSchedule::new();
         loop {
-            let info = get_charge_info();
-            let value = match info {
+            let sampler_args = current_args(&sampler_settings);
+            let filter = sensor_filter(&sampler_args);
+            let info = source.read(&read_options(&sampler_args)).await;
+            match &info {
+                Ok((_, attributes)) => {
+                    failures = 0;
+                    read_backend = attributes
+                        .backend
+                        .clone()
+                        .or_else(|| (!ac_only).then(|| backend.clone()));
+                }
+                Err(e) => {
+                    failures += 1;
+                    last_error = Some((format!("{:#}", e), Utc::now()));
+                }
+            }
+            let now_reported = Some((failures, read_backend.clone()));
+            if reported != now_reported && !sampler_paused.load(Ordering::Relaxed) {
+                reported = now_reported;
+                let diagnostics = ReadDiagnostics {
+                    backend: read_backend.as_deref(),
+                    consecutive_failures: failures,
+                    last_error: last_error.as_ref().map(|(e, _)| e.as_str()),
+                    last_error_at: last_error
+                        .as_ref()
+                        .map(|(_, at)| sampler_args.timestamp_format.at(*at)),
+                };
+                let message = MessageBuilder::new()
+                    .payload(serde_json::to_string(&diagnostics).unwrap_or_default())
+                    .topic(diagnostics_topic(&topic))
+                    .retain(true)
+                    .build();
+                if tx.send(message).await.is_err() {
+                    error!("receiver dropped")
+                }
+            }
+            let known = info.is_ok();
+            let present = known || sampler_args.publish_unknown;
+            let was_present = sampler_present.swap(present, Ordering::Relaxed);
+            if was_present != present {
+                let message = MessageBuilder::new()
+                    .payload(String::from(if present { "online" } else { "offline" }))
+                    .topic(sampler_availability_topic.clone())
+                    .retain(true)
+                    .build();
+                if tx.send(message).await.is_err() {
+                    error!("receiver dropped")
+                }
+            }
+            let (mut value, mut attributes) = match info {
                 Ok(x) => x,
-                Err(_) => ChargeInfo {
-                    percentage: 0.0,
-                    state: State::Unknown,
-                },
+                Err(e) if !sampler_args.publish_unknown => {
+                    if was_present {
+                        warn!("{:#}", e);
+                    }
+                    forced = wait(&sampler_args, tier).await;
+                    continue;
+                }
+                Err(_) => (ChargeInfo::unknown(), BatteryAttributes::default()),
+            };
+            if sampler_args.stable_id {
+                value.key_by_serial();
+            }
+            #[cfg(target_os = "linux")]
+            if known && sampler_args.platform {
+                match platform::read().await {
+                    Ok((profile, lid_closed)) => {
+                        value.power_profile = profile;
+                        value.lid_closed = lid_closed;
+                    }
+                    Err(e) => warn!("{:#}", e),
+                }
+            }
+            let known = known && !ac_only;
+            if known && attributes.backend.is_none() {
+                attributes.backend = Some(backend.clone());
+            }
+            if known && attributes.temperature.is_none() {
+                attributes.temperature =
+                    thermal::battery_temperature(sampler_args.temperature_sensor.as_deref());
+            }
+            let paused = sampler_paused.load(Ordering::Relaxed);
+            if !paused && prev_attributes.as_ref() != Some(&attributes) {
+                let message = MessageBuilder::new()
+                    .payload(filter.json("", &attributes))
+                    .topic(attributes_topic.clone())
+                    .retain(true)
+                    .build();
+                if tx.send(message).await.is_err() {
+                    error!("receiver dropped")
+                }
+                prev_attributes = Some(attributes);
+            }
+            if let Some(limit) = sampler_args.charge_limit {
+                if value.state == State::Charging {
+                    match last_learned {
+                        // Waits for the charge to move, so readings that
+                        // repeat it do not shorten the elapsed time.
+                        Some((from, _)) if from == value.percentage => {}
+                        Some((from, at)) => {
+                            curve.learn(from, value.percentage, at.elapsed());
+                            last_learned = Some((value.percentage, time::Instant::now()));
+                        }
+                        None => last_learned = Some((value.percentage, time::Instant::now())),
+                    }
+                    value.time_to_target = curve.eta(value.percentage, f32::from(limit));
+                } else {
+                    last_learned = None;
+                }
+            }
+            if sampler_args.rate_samples != rate_samples {
+                rate_samples = sampler_args.rate_samples;
+                rate_average = RateAverage::new(rate_samples);
+            }
+            if known {
+                value.sign_flows();
+                value.energy_rate_smoothed =
+                    Some(rate_average.update(value.energy_rate, value.state));
+                let target = value.charge_end_threshold.map_or(100.0, f32::from);
+                if let Some((seconds, confidence)) =
+                    remaining.update(value.percentage, value.state, target)
+                {
+                    value.time_remaining = Some(seconds);
+                    value.time_remaining_confidence = Some(confidence);
+                }
+                if let Some((needs_calibration, spread)) = calibration.update(&value) {
+                    value.needs_calibration = Some(needs_calibration);
+                    value.energy_full_spread = Some(spread);
+                }
+            }
+            tier = if known {
+                PollTier::of(&value, &sampler_args)
+            } else {
+                PollTier::Normal
             };
-            if value != prev_info {
-                let payload = match serde_json::to_string(&value) {
-                    Ok(j) => j,
-                    _ => String::from("parsing error"),
+            if let Some(decimals) = sampler_args.precision {
+                value.round(decimals);
+            }
+            let period_up = schedule.apply(&sampler_args.settings.sensors, &mut value, &prev_info);
+            let due = forced
+                || period_up
+                || value.changed_from(&prev_info, sampler_args.min_change)
+                || last_publish.elapsed() >= heartbeat(&sampler_args);
+            if !paused && due {
+                let published = value.in_units(&sampler_args);
+                let state = StatePayload {
+                    info: &published,
+                    last_updated: Timestamp::now(sampler_args.timestamp_format),
                 };
+                let payload = filter.json("", &state);
                 let message = MessageBuilder::new()
                     .payload(payload.clone())
                     .topic(state_topic.clone())
                     .retain(true)
                     .build();
-                if let Err(_) = tx.send(message).await {
-                    println!("receiver dropped")
+                if tx.send(message).await.is_err() {
+                    error!("receiver dropped")
+                }
+                let unannounced = value.batteries.len() > 1
+                    && value.batteries.keys().any(|name| !announced.contains(name));
+                if unannounced && !sampler_args.no_discovery {
+                    announced.extend(value.batteries.keys().cloned());
+                    let host = Host::read(&sampler_args).await;
+                    let messages = discovery_messages(&sampler_args, &host);
+                    if let Ok(mut discoveries) = sampler_discoveries.lock() {
+                        *discoveries = messages.clone();
+                    }
+                    for message in messages {
+                        if tx.send(message).await.is_err() {
+                            error!("receiver dropped")
+                        }
+                    }
+                }
+                // Packs keep their entities updated while another comes and
+                // goes, even when only one is left.
+                let announced_batteries = published
+                    .batteries
+                    .iter()
+                    .filter(|(name, _)| announced.contains(*name));
+                for (name, battery) in announced_batteries {
+                    let state = StatePayload {
+                        info: battery,
+                        last_updated: state.last_updated,
+                    };
+                    let message = MessageBuilder::new()
+                        .payload(filter.json("", &state))
+                        .topic(battery_state_topic(&topic, name))
+                        .retain(true)
+                        .build();
+                    if tx.send(message).await.is_err() {
+                        error!("receiver dropped")
+                    }
+                }
+                // Read again from whichever backend produced this reading.
+                let active = prev_attributes
+                    .as_ref()
+                    .and_then(|attributes| attributes.backend.as_deref())
+                    .and_then(|name| Backend::from_str(name, true).ok());
+                if let Some(active) = active.filter(|_| sampler_args.debug_topic && known) {
+                    match active.source().raw().await {
+                        Ok(raw) => {
+                            let message = MessageBuilder::new()
+                                .payload(raw.to_string())
+                                .topic(raw_topic(&topic))
+                                .build();
+                            if tx.send(message).await.is_err() {
+                                error!("receiver dropped")
+                            }
+                        }
+                        Err(e) => warn!("reading raw backend data: {:#}", e),
+                    }
                 }
                 prev_info = value;
+                last_publish = time::Instant::now();
             }
-            time::sleep(Duration::from_secs(60)).await;
+            forced = wait(&sampler_args, tier).await;
         }
     });
 
+    let sender = publisher.clone();
     task::spawn(async move {
-        loop {
-            if let Some(info) = rx.recv().await {
-                mqtt_send(client.clone(), info).await;
-            };
-            time::sleep(Duration::from_secs(60)).await;
+        while let Some(info) = rx.recv().await {
+            mqtt_send(sender.clone(), info).await;
         }
     });
+    if dry_run {
+        // What connecting to the broker would publish.
+        let availability = MessageBuilder::new()
+            .topic(availability_topic)
+            .payload(String::from("online"))
+            .retain(true)
+            .build();
+        mqtt_send(publisher.clone(), availability).await;
+        publish_all(publisher, current(&discoveries)).await;
+        return future::pending().await;
+    }
     loop {
-        match eventloop.poll().await {
+        let event = eventloop.poll().await;
+        let CommandTopics {
+            threshold: threshold_topic,
+            charge: charge_topic,
+            behaviour: behaviour_topic,
+        } = current_commands(&commands);
+        match event {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                let availability = if battery_present.load(Ordering::Relaxed) {
+                    "online"
+                } else {
+                    "offline"
+                };
+                if let Err(e) = availability_client.try_publish(
+                    &availability_topic,
+                    QoS::AtLeastOnce,
+                    true,
+                    availability,
+                ) {
+                    error!(error = ?e, "queueing the message failed");
+                }
+                let state = if paused.load(Ordering::Relaxed) {
+                    "ON"
+                } else {
+                    "OFF"
+                };
+                if let Err(e) =
+                    availability_client.try_publish(&paused_topic, QoS::AtLeastOnce, true, state)
+                {
+                    error!(error = ?e, "queueing the message failed");
+                }
+                for command_topic in [&refresh_topic, &paused_command_topic] {
+                    if let Err(e) =
+                        availability_client.try_subscribe(command_topic, QoS::AtLeastOnce)
+                    {
+                        error!(error = ?e, "queueing the message failed");
+                    }
+                }
+                for command_topic in current_commands(&commands).iter() {
+                    if let Err(e) =
+                        availability_client.try_subscribe(command_topic, QoS::AtLeastOnce)
+                    {
+                        error!(error = ?e, "queueing the message failed");
+                    }
+                }
+                // Retained configs may have been purged while we were away.
+                task::spawn(publish_all(publisher.clone(), current(&discoveries)));
+            }
+            Ok(Event::Incoming(Packet::Publish(p))) if p.topic == refresh_topic => {
+                refresh.notify_one();
+            }
+            Ok(Event::Incoming(Packet::Publish(p))) if p.topic == paused_command_topic => {
+                let pause = match &p.payload[..] {
+                    b"ON" => true,
+                    b"OFF" => false,
+                    other => {
+                        warn!(
+                            "ignoring pause command {:?}",
+                            String::from_utf8_lossy(other)
+                        );
+                        continue;
+                    }
+                };
+                paused.store(pause, Ordering::Relaxed);
+                let state = if pause { "ON" } else { "OFF" };
+                if let Err(e) =
+                    availability_client.try_publish(&paused_topic, QoS::AtLeastOnce, true, state)
+                {
+                    error!(error = ?e, "queueing the message failed");
+                }
+                if !pause {
+                    refresh.notify_one();
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(p)))
+                if threshold_topic.as_ref() == Some(&p.topic) =>
+            {
+                match parse_threshold(&p.payload) {
+                    Ok(value) => {
+                        if apply_threshold(value) {
+                            refresh.notify_one();
+                        }
+                    }
+                    Err(e) => warn!(error = %e, "ignoring charge end threshold"),
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(p))) if charge_topic.as_ref() == Some(&p.topic) => {
+                match parse_charge_command(&p.payload) {
+                    Ok(value) => {
+                        if apply_threshold(value) {
+                            refresh.notify_one();
+                        }
+                    }
+                    Err(e) => warn!(error = %e, "ignoring charge command"),
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(p)))
+                if behaviour_topic.as_ref() == Some(&p.topic) =>
+            {
+                if apply_charge_behaviour(String::from_utf8_lossy(&p.payload).trim()) {
+                    refresh.notify_one();
+                }
+            }
             Ok(_) => (),
-            Err(e) => println!("{:?}", e),
+            Err(e) => error!(
+                error = %e,
+                kind = logging::error_kind(&e),
+                "connection to the broker failed"
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn object_ids_are_lowercase_with_single_underscores() {
+        assert_eq!(sanitize_object_id("My Laptop"), "my_laptop");
+        assert_eq!(sanitize_object_id("host.example.com"), "host_example_com");
+        assert_eq!(sanitize_object_id("  BAT0 / ACAD  "), "bat0_acad");
+        assert_eq!(sanitize_object_id("build-01"), "build-01");
+        assert_eq!(sanitize_object_id("Ünïcode"), "n_code");
+        assert_eq!(sanitize_object_id("..."), "");
+    }
+
+    #[test]
+    fn sensor_filters_know_every_entity_key() {
+        let options: [&[&str]; 2] = [
+            &["--ups", "ups", "--charge-limit", "80", "--platform"],
+            &["--ac-only"],
+        ];
+        for options in options {
+            let args = Args::parse_from(
+                std::iter::once("battery-monitor-daemon").chain(options.iter().copied()),
+            );
+            for entity in entities(&args, &Host::new(&args, None)) {
+                assert!(
+                    sensors::ENTITY_KEYS.contains(&entity.key),
+                    "{} is missing from ENTITY_KEYS",
+                    entity.key
+                );
+            }
         }
     }
 }
@@ -0,0 +1,167 @@
+use crate::round_to;
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::{collections::BTreeMap, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time,
+};
+
+const DEFAULT_PORT: u16 = 3493;
+
+/// How long upsd gets to answer before the UPS is skipped for this poll.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A UPS served by NUT's upsd, written `upsname@hostname[:port]` like NUT's
+/// own tools do.
+#[derive(Clone, PartialEq)]
+pub(crate) struct Ups {
+    pub(crate) name: String,
+    host: String,
+    port: u16,
+}
+
+pub(crate) fn parse_ups(s: &str) -> Result<Ups, String> {
+    let (name, server) = s.split_once('@').unwrap_or((s, "localhost"));
+    let (host, port) = match server.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            port.parse()
+                .map_err(|_| format!("invalid port {:?}", port))?,
+        ),
+        None => (server, DEFAULT_PORT),
+    };
+    if name.is_empty() || host.is_empty() {
+        return Err(format!("expected UPS[@HOST[:PORT]], got {:?}", s));
+    }
+    Ok(Ups {
+        name: name.to_string(),
+        host: host.to_string(),
+        port,
+    })
+}
+
+/// The UPS variables published to `<topic>/ups/<name>/state`.
+#[derive(PartialEq, Serialize)]
+pub(crate) struct UpsInfo {
+    /// Percent.
+    charge: Option<f32>,
+    /// Percent of the rated output.
+    load: Option<f32>,
+    /// Seconds left on battery.
+    runtime: Option<f32>,
+    /// NUT status flags, e.g. "OL CHRG".
+    status: Option<String>,
+    input_voltage: Option<f32>,
+    battery_voltage: Option<f32>,
+}
+
+impl UpsInfo {
+    pub(crate) fn round(&mut self, decimals: u8) {
+        let round = |value: Option<f32>| value.map(|v| round_to(v, decimals));
+        self.charge = round(self.charge);
+        self.load = round(self.load);
+        self.runtime = round(self.runtime);
+        self.input_voltage = round(self.input_voltage);
+        self.battery_voltage = round(self.battery_voltage);
+    }
+
+    fn from_vars(vars: &BTreeMap<String, String>) -> UpsInfo {
+        let number = |var: &str| vars.get(var).and_then(|value| value.parse().ok());
+        UpsInfo {
+            charge: number("battery.charge"),
+            load: number("ups.load"),
+            runtime: number("battery.runtime"),
+            status: vars.get("ups.status").cloned(),
+            input_voltage: number("input.voltage"),
+            battery_voltage: number("battery.voltage"),
+        }
+    }
+}
+
+/// Parses a `VAR <ups> <name> "<value>"` line of a `LIST VAR` reply.
+fn parse_var(line: &str) -> Option<(String, String)> {
+    let rest = line.strip_prefix("VAR ")?;
+    let (_, rest) = rest.split_once(' ')?;
+    let (name, value) = rest.split_once(' ')?;
+    let value = value.strip_prefix('"')?.strip_suffix('"')?;
+    Some((
+        name.to_string(),
+        value.replace("\\\"", "\"").replace("\\\\", "\\"),
+    ))
+}
+
+async fn list_vars(ups: &Ups) -> Result<BTreeMap<String, String>> {
+    let stream = TcpStream::connect((ups.host.as_str(), ups.port))
+        .await
+        .with_context(|| format!("connecting to upsd at {}:{}", ups.host, ups.port))?;
+    let (reader, mut writer) = stream.into_split();
+    writer
+        .write_all(format!("LIST VAR {}\n", ups.name).as_bytes())
+        .await?;
+    let mut lines = BufReader::new(reader).lines();
+    let end = format!("END LIST VAR {}", ups.name);
+    let mut vars = BTreeMap::new();
+    while let Some(line) = lines.next_line().await? {
+        if line == end {
+            let _ = writer.write_all(b"LOGOUT\n").await;
+            return Ok(vars);
+        }
+        if let Some(error) = line.strip_prefix("ERR ") {
+            bail!("upsd refused {}: {}", ups.name, error);
+        }
+        vars.extend(parse_var(&line));
+    }
+    bail!("upsd closed the connection while listing {}", ups.name)
+}
+
+/// Reads the variables of one UPS from upsd.
+pub(crate) async fn read(ups: &Ups) -> Result<UpsInfo> {
+    let vars = time::timeout(TIMEOUT, list_vars(ups))
+        .await
+        .with_context(|| format!("upsd did not answer for {}", ups.name))??;
+    Ok(UpsInfo::from_vars(&vars))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts(s: &str) -> Result<(String, String, u16), String> {
+        parse_ups(s).map(|ups| (ups.name, ups.host, ups.port))
+    }
+
+    #[test]
+    fn ups_defaults_to_localhost_and_the_nut_port() {
+        assert_eq!(
+            parts("ups"),
+            Ok(("ups".into(), "localhost".into(), DEFAULT_PORT))
+        );
+        assert_eq!(
+            parts("ups@nas"),
+            Ok(("ups".into(), "nas".into(), DEFAULT_PORT))
+        );
+        assert_eq!(
+            parts("ups@nas:3494"),
+            Ok(("ups".into(), "nas".into(), 3494))
+        );
+        assert!(parse_ups("@nas").is_err());
+        assert!(parse_ups("ups@").is_err());
+        assert!(parse_ups("ups@nas:port").is_err());
+    }
+
+    #[test]
+    fn vars_are_unquoted_and_unescaped() {
+        assert_eq!(
+            parse_var(r#"VAR ups battery.charge "100""#),
+            Some(("battery.charge".to_string(), "100".to_string()))
+        );
+        assert_eq!(
+            parse_var(r#"VAR ups ups.mfr "A \"quoted\" \\ name""#),
+            Some(("ups.mfr".to_string(), r#"A "quoted" \ name"#.to_string()))
+        );
+        assert_eq!(parse_var("BEGIN LIST VAR ups"), None);
+        assert_eq!(parse_var("VAR ups battery.charge 100"), None);
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{
+    attributes_topic, availability_topic, discovery_messages, logging, mqtt_options, power_source,
+    print_message, read_options, sensor_filter, source::Reading, Args, Host, Message,
+    MessageBuilder, StatePayload, Timestamp,
+};
+use anyhow::Result;
+use rumqttc::{AsyncClient, Event, Packet, QoS};
+use std::time::Duration;
+use tokio::{task, time};
+use tracing::{error, info, warn};
+
+/// Exit status when the broker did not acknowledge every message.
+const BROKER_FAILED: i32 = 1;
+/// Exit status when no battery could be read; the entities were marked
+/// unavailable.
+const NO_BATTERY: i32 = 3;
+
+/// How long the broker gets to acknowledge everything.
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The messages a single `reading` publishes: the availability and, with a
+/// battery, its state and attributes.
+fn messages(args: &Args, reading: Result<Reading>) -> (Vec<Message>, bool) {
+    let availability = |online: bool| {
+        MessageBuilder::new()
+            .topic(availability_topic(&args.topic))
+            .payload(String::from(if online { "online" } else { "offline" }))
+            .retain(true)
+            .build()
+    };
+    let (mut info, attributes) = match reading {
+        Ok(reading) => reading,
+        Err(e) => {
+            warn!("{:#}", e);
+            return (vec![availability(false)], false);
+        }
+    };
+    if !args.ac_only {
+        info.sign_flows();
+    }
+    if args.stable_id {
+        info.key_by_serial();
+    }
+    if let Some(decimals) = args.precision {
+        info.round(decimals);
+    }
+    let info = info.in_units(args);
+    let filter = sensor_filter(args);
+    let state = StatePayload {
+        info: &info,
+        last_updated: Timestamp::now(args.timestamp_format),
+    };
+    let messages = vec![
+        MessageBuilder::new()
+            .topic(format!("{}/state", args.topic))
+            .payload(filter.json("", &state))
+            .retain(true)
+            .build(),
+        MessageBuilder::new()
+            .topic(attributes_topic(&args.topic))
+            .payload(filter.json("", &attributes))
+            .retain(true)
+            .build(),
+        availability(true),
+    ];
+    (messages, true)
+}
+
+/// Publishes `messages` with a client of its own, identified by `client_id`
+/// after the topic, and waits for the broker to acknowledge every one,
+/// returning whether it did. With `--dry-run` they are printed instead.
+pub(crate) async fn publish_and_wait_for_acks(
+    args: &Args,
+    client_id: &str,
+    messages: Vec<Message>,
+) -> bool {
+    if args.dry_run {
+        messages.iter().for_each(print_message);
+        return true;
+    }
+    let options = mqtt_options(args, format!("{}-{}", crate::client_id(args), client_id));
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    let publisher = client.clone();
+    let count = messages.len();
+    task::spawn(async move {
+        for message in messages {
+            if let Err(e) = publisher
+                .publish(
+                    message.topic,
+                    QoS::AtLeastOnce,
+                    message.retain,
+                    message.payload,
+                )
+                .await
+            {
+                error!(error = ?e, "queueing the message failed");
+            }
+        }
+    });
+
+    let acks = async {
+        let mut acked = 0;
+        while acked < count {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::PubAck(_))) => acked += 1,
+                Ok(_) => (),
+                Err(e) => {
+                    error!(
+                        error = %e,
+                        kind = logging::error_kind(&e),
+                        "connection to the broker failed"
+                    );
+                    return false;
+                }
+            }
+        }
+        true
+    };
+    let acked = time::timeout(ACK_TIMEOUT, acks).await.unwrap_or_else(|_| {
+        error!(
+            "broker did not acknowledge everything within {}s",
+            ACK_TIMEOUT.as_secs()
+        );
+        false
+    });
+    // Without blocking: the queue may still be full after a failure.
+    let _ = client.try_disconnect();
+    acked
+}
+
+/// Reads the battery once, publishes it (plus the discovery configs with
+/// `discovery`) and waits for the broker to acknowledge every message,
+/// returning the exit status.
+pub(crate) async fn run(args: &Args, discovery: bool) -> i32 {
+    let reading = power_source(args).read(&read_options(args)).await;
+    let host = Host::new(args, reading.as_ref().ok());
+    let (mut messages, found) = messages(args, reading);
+    if discovery && !args.no_discovery {
+        messages.splice(0..0, discovery_messages(args, &host));
+    }
+    let count = messages.len();
+    if !publish_and_wait_for_acks(args, "once", messages).await {
+        BROKER_FAILED
+    } else if !found {
+        NO_BATTERY
+    } else {
+        info!("published {} messages", count);
+        0
+    }
+}
+
+/// Publishes the discovery configs, reading the battery only for the device
+/// details, returning whether the broker acknowledged all of them.
+pub(crate) async fn discover(args: &Args) -> bool {
+    if args.no_discovery {
+        error!("--no-discovery leaves nothing to publish");
+        return false;
+    }
+    let messages = discovery_messages(args, &Host::read(args).await);
+    let count = messages.len();
+    let published = publish_and_wait_for_acks(args, "discover", messages).await;
+    if published {
+        info!("published {} discovery configs", count);
+    }
+    published
+}
@@ -0,0 +1,24 @@
+use serde::Serialize;
+
+/// A battery-powered device attached to the host, like a mouse or headset.
+pub(crate) struct Peripheral {
+    /// Stable id used in topics and unique ids, e.g. "bt_a0_b1_c2_d3_e4_f5".
+    pub(crate) id: String,
+    /// Name shown in Home Assistant, e.g. "MX Master 3".
+    pub(crate) name: String,
+    pub(crate) state: PeripheralState,
+}
+
+/// Published to `<topic>/peripherals/<id>/state`.
+#[derive(PartialEq, Serialize, Clone)]
+pub(crate) struct PeripheralState {
+    pub(crate) percentage: f32,
+    /// Charging status in lowercase, when the source reports one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) status: Option<String>,
+}
+
+/// Turns a name or address into an id usable in topics and object ids.
+pub(crate) fn id(prefix: &str, raw: &str) -> String {
+    format!("{}_{}", prefix, crate::sanitize_object_id(raw))
+}
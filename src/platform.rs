@@ -0,0 +1,83 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use std::fs;
+use zbus::{Connection, Proxy};
+
+/// Reads a property, treating a missing service or property as unset.
+async fn property<T>(
+    conn: &Connection,
+    service: &str,
+    path: &str,
+    interface: &str,
+    name: &str,
+) -> Option<T>
+where
+    T: TryFrom<zbus::zvariant::OwnedValue>,
+    T::Error: Into<zbus::Error>,
+{
+    let proxy = Proxy::new(conn, service, path, interface).await.ok()?;
+    proxy.get_property(name).await.ok()
+}
+
+/// The active profile of power-profiles-daemon, e.g. "balanced" or
+/// "power-saver", under its current name or the older net.hadess one.
+async fn power_profile(conn: &Connection) -> Option<String> {
+    let current = property(
+        conn,
+        "org.freedesktop.UPower.PowerProfiles",
+        "/org/freedesktop/UPower/PowerProfiles",
+        "org.freedesktop.UPower.PowerProfiles",
+        "ActiveProfile",
+    );
+    match current.await {
+        Some(profile) => Some(profile),
+        None => {
+            property(
+                conn,
+                "net.hadess.PowerProfiles",
+                "/net/hadess/PowerProfiles",
+                "net.hadess.PowerProfiles",
+                "ActiveProfile",
+            )
+            .await
+        }
+    }
+}
+
+/// Whether logind reports the lid closed, or `None` without a lid.
+async fn lid_closed(conn: &Connection) -> Option<bool> {
+    let login = |name| {
+        property::<bool>(
+            conn,
+            "org.freedesktop.login1",
+            "/org/freedesktop/login1",
+            "org.freedesktop.login1.Manager",
+            name,
+        )
+    };
+    if !login("LidPresent").await? {
+        return None;
+    }
+    login("LidClosed").await
+}
+
+/// The power profile and lid state, which change how fast the battery drains.
+pub(crate) async fn read() -> Result<(Option<String>, Option<bool>)> {
+    let conn = Connection::system()
+        .await
+        .context("connecting to the system bus")?;
+    Ok((power_profile(&conn).await, lid_closed(&conn).await))
+}
+
+/// When the machine booted, from the `btime` line of /proc/stat, which unlike
+/// the uptime stays the same between reads.
+pub(crate) fn boot_time() -> Option<DateTime<Utc>> {
+    let stat = fs::read_to_string("/proc/stat").ok()?;
+    let seconds = stat
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()?;
+    DateTime::from_timestamp(seconds, 0)
+}
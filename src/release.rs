@@ -0,0 +1,53 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+/// State of the Home Assistant update entity.
+#[derive(Serialize)]
+pub(crate) struct VersionState {
+    pub(crate) installed_version: String,
+    pub(crate) latest_version: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) release_url: Option<String>,
+}
+
+impl VersionState {
+    /// The running version, reported as the latest one until a release has
+    /// been fetched.
+    pub(crate) fn installed() -> VersionState {
+        VersionState {
+            installed_version: String::from(env!("CARGO_PKG_VERSION")),
+            latest_version: String::from(env!("CARGO_PKG_VERSION")),
+            release_url: None,
+        }
+    }
+}
+
+/// Fetches the latest release from `url`, which may serve either a GitHub
+/// release as JSON or just the version as plain text.
+pub(crate) fn fetch(url: &str) -> Result<VersionState> {
+    let body = ureq::get(url)
+        .call()
+        .with_context(|| format!("fetching {}", url))?
+        .body_mut()
+        .read_to_string()
+        .with_context(|| format!("reading {}", url))?;
+    let (version, release_url) = match serde_json::from_str::<serde_json::Value>(&body) {
+        Ok(release) => (
+            release
+                .get("tag_name")
+                .and_then(|tag| tag.as_str())
+                .context("release has no tag_name")?
+                .to_string(),
+            release
+                .get("html_url")
+                .and_then(|url| url.as_str())
+                .map(String::from),
+        ),
+        Err(_) => (body.trim().to_string(), None),
+    };
+    Ok(VersionState {
+        latest_version: version.trim_start_matches('v').to_string(),
+        release_url,
+        ..VersionState::installed()
+    })
+}
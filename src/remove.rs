@@ -0,0 +1,133 @@
+use crate::{
+    client_id, discovery_messages, logging, mqtt_options, once::publish_and_wait_for_acks, Args,
+    Host, MessageBuilder,
+};
+use rumqttc::{AsyncClient, Event, Packet, QoS};
+use std::{collections::BTreeSet, time::Duration};
+use tokio::time;
+use tracing::{error, info};
+
+/// How long the broker may stay quiet before all retained configs are assumed
+/// to have been delivered.
+const RETAINED_QUIET: Duration = Duration::from_secs(2);
+
+/// Whether a retained discovery config belongs to this host, judged by its
+/// device (or that of its components, for device-based discovery) carrying
+/// this host's identifier. Topics cannot tell, as every host on the default
+/// topic shares them. Abbreviated keys are recognized too.
+fn is_ours(payload: &[u8], identifier: &str) -> bool {
+    let Ok(config) = serde_json::from_slice::<serde_json::Value>(payload) else {
+        return false;
+    };
+    let of_this_host = |config: &serde_json::Value| {
+        config
+            .get("device")
+            .or_else(|| config.get("dev"))
+            .and_then(|device| device.get("identifiers").or_else(|| device.get("ids")))
+            .is_some_and(|ids| match ids {
+                serde_json::Value::Array(ids) => ids.iter().any(|id| id == identifier),
+                id => id == identifier,
+            })
+    };
+    let components = config
+        .get("components")
+        .or_else(|| config.get("cmps"))
+        .and_then(|value| value.as_object());
+    of_this_host(&config) || components.is_some_and(|c| c.values().any(of_this_host))
+}
+
+/// The retained discovery configs on the broker that belong to this host,
+/// or none when the broker could not be asked.
+async fn retained_topics(args: &Args, host: &Host) -> Option<BTreeSet<String>> {
+    let options = mqtt_options(args, format!("{}-remove", client_id(args)));
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    let filter = format!("{}/#", args.discovery_topic);
+    if let Err(e) = client.subscribe(&filter, QoS::AtLeastOnce).await {
+        error!(error = ?e, "queueing the message failed");
+        return None;
+    }
+
+    let mut topics = BTreeSet::new();
+    loop {
+        match time::timeout(RETAINED_QUIET, eventloop.poll()).await {
+            Ok(Ok(Event::Incoming(Packet::Publish(p)))) => {
+                if p.retain && p.topic.ends_with("/config") && is_ours(&p.payload, &host.object_id)
+                {
+                    topics.insert(p.topic);
+                }
+            }
+            Ok(Ok(_)) => (),
+            Ok(Err(e)) => {
+                error!(error = %e, kind = logging::error_kind(&e), "connection to the broker failed");
+                return None;
+            }
+            Err(_) => break,
+        }
+    }
+    // Dropping the event loop closes the connection before the configs are
+    // cleared on one of its own.
+    Some(topics)
+}
+
+/// Clears every retained discovery config that belongs to this daemon: the
+/// ones the current options would publish plus any older ones still retained
+/// on the broker, e.g. for sensors that have since been disabled. With
+/// `--dry-run` the topics are only printed, though still looked up on the
+/// broker.
+pub(crate) async fn run(args: &Args) -> bool {
+    let host = Host::read(args).await;
+    let mut topics: BTreeSet<String> = discovery_messages(args, &host)
+        .into_iter()
+        .map(|message| message.topic)
+        .collect();
+    match retained_topics(args, &host).await {
+        Some(retained) => topics.extend(retained),
+        None => return false,
+    }
+
+    if args.dry_run {
+        for topic in &topics {
+            println!("{}", topic);
+        }
+        return true;
+    }
+
+    let messages = topics
+        .iter()
+        .map(|topic| {
+            info!(topic = %topic, "removing");
+            MessageBuilder::new()
+                .topic(topic.clone())
+                .payload(String::new())
+                .retain(true)
+                .build()
+        })
+        .collect();
+    let removed = publish_and_wait_for_acks(args, "remove", messages).await;
+    if removed {
+        info!("removed {} discovery configs", topics.len());
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_ours_matches_device_identifier() {
+        let entity = br#"{"state_topic":"battery-daemon/status/battery/state","device":{"identifiers":["laptop"]}}"#;
+        assert!(is_ours(entity, "laptop"));
+        assert!(!is_ours(entity, "desktop"));
+    }
+
+    #[test]
+    fn is_ours_reads_abbreviated_and_device_based_configs() {
+        let abbreviated =
+            br#"{"stat_t":"battery-daemon/status/battery/state","dev":{"ids":["laptop"]}}"#;
+        assert!(is_ours(abbreviated, "laptop"));
+        let device = br#"{"dev":{"ids":"laptop"},"cmps":{"laptop_percentage":{"p":"sensor"}}}"#;
+        assert!(is_ours(device, "laptop"));
+        assert!(!is_ours(b"not json", "laptop"));
+    }
+}
@@ -0,0 +1,158 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+/// Payload keys kept whatever the lists say, as consumers need to know how
+/// fresh the rest is.
+const ALWAYS_KEPT: &[&str] = &["last_updated"];
+
+/// The keys of every entity `discovery_configs` can announce, UPS ones with
+/// their `ups_` prefix. `--include-sensor` only leaves out payload values
+/// named like one of these; the others, like `state` or `vendor`, have no
+/// entity to include them by.
+pub(crate) const ENTITY_KEYS: &[&str] = &[
+    "percentage",
+    "energy_rate",
+    "power",
+    "current",
+    "energy_now",
+    "energy_full",
+    "energy_full_design",
+    "health",
+    "cycle_count",
+    "voltage",
+    "energy_rate_smoothed",
+    "energy_full_spread",
+    "needs_calibration",
+    "temperature",
+    "time_remaining",
+    "time_remaining_confidence",
+    "time_to_target",
+    "read_error",
+    "read_failures",
+    "last_updated",
+    "capacity_level",
+    "charger_type",
+    "input_current_limit",
+    "input_voltage_limit",
+    "power_profile",
+    "lid_closed",
+    "ac_connected",
+    "charge_start_threshold",
+    "charge_end_threshold_applied",
+    "charge_end_threshold",
+    "charge_behaviour",
+    "refresh",
+    "paused",
+    "version",
+    "boot_time",
+    "ups_charge",
+    "ups_load",
+    "ups_runtime",
+    "ups_status",
+    "ups_input_voltage",
+];
+
+/// Which sensors are announced and published, from `--include-sensor` and
+/// `--exclude-sensor`.
+#[derive(Clone, Default)]
+pub(crate) struct SensorFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl SensorFilter {
+    pub(crate) fn new(include: &[String], exclude: &[String]) -> SensorFilter {
+        SensorFilter {
+            include: include.to_vec(),
+            exclude: exclude.to_vec(),
+        }
+    }
+
+    /// Whether the sensor named `key` is announced.
+    pub(crate) fn enabled(&self, key: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|k| k == key))
+            && !self.exclude.iter().any(|k| k == key)
+    }
+
+    /// Whether the payload value named `key` may leave the machine: values
+    /// without an entity are only left out when excluded.
+    fn kept(&self, key: &str) -> bool {
+        ALWAYS_KEPT.contains(&key)
+            || if ENTITY_KEYS.contains(&key) {
+                self.enabled(key)
+            } else {
+                !self.exclude.iter().any(|k| k == key)
+            }
+    }
+
+    /// Serializes `value` to JSON without the values of disabled sensors.
+    /// `prefix` maps payload keys to sensor keys, e.g. `ups_` for the UPS
+    /// payload's `charge`.
+    pub(crate) fn json<T: Serialize>(&self, prefix: &str, value: &T) -> String {
+        if self.include.is_empty() && self.exclude.is_empty() {
+            return serde_json::to_string(value).unwrap_or_default();
+        }
+        let mut value = match serde_json::to_value(value) {
+            Ok(value) => value,
+            Err(_) => return String::new(),
+        };
+        if let Value::Object(map) = &mut value {
+            self.strip(prefix, map);
+        }
+        value.to_string()
+    }
+
+    fn strip(&self, prefix: &str, map: &mut Map<String, Value>) {
+        map.retain(|key, _| self.kept(&format!("{}{}", prefix, key)));
+        if let Some(Value::Object(batteries)) = map.get_mut("batteries") {
+            for battery in batteries.values_mut() {
+                if let Value::Object(battery) = battery {
+                    self.strip(prefix, battery);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn filtered(filter: &SensorFilter, value: Value) -> Value {
+        serde_json::from_str(&filter.json("", &value)).unwrap()
+    }
+
+    #[test]
+    fn including_sensors_keeps_values_without_entities() {
+        let filter = SensorFilter::new(&[String::from("percentage")], &[]);
+        let payload = json!({
+            "percentage": 80.0,
+            "voltage": 12.1,
+            "state": "charging",
+            "last_updated": "2024-05-01T12:00:00Z",
+        });
+        assert_eq!(
+            filtered(&filter, payload),
+            json!({
+                "percentage": 80.0,
+                "state": "charging",
+                "last_updated": "2024-05-01T12:00:00Z",
+            })
+        );
+    }
+
+    #[test]
+    fn excluding_applies_to_any_value_and_each_battery() {
+        let filter = SensorFilter::new(&[], &[String::from("voltage"), String::from("vendor")]);
+        let payload = json!({
+            "voltage": 12.1,
+            "vendor": "ACME",
+            "batteries": {"bat0": {"voltage": 12.1, "percentage": 80.0}},
+        });
+        assert_eq!(
+            filtered(&filter, payload),
+            json!({"batteries": {"bat0": {"percentage": 80.0}}})
+        );
+    }
+}
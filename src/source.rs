@@ -0,0 +1,388 @@
+use crate::{config::Aggregate, get_charge_info, BatteryAttributes, ChargeInfo};
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use futures_lite::future::{self, Boxed};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+/// The combined reading of the system batteries plus details of the first.
+pub(crate) type Reading = (ChargeInfo, BatteryAttributes);
+
+/// Which batteries to read and how to combine them.
+#[derive(Clone, Default)]
+pub(crate) struct ReadOptions {
+    pub(crate) aggregate: Aggregate,
+    /// Kernel names of the batteries to read, e.g. "bat1"; all when empty.
+    pub(crate) batteries: Vec<String>,
+}
+
+impl ReadOptions {
+    pub(crate) fn selects(&self, name: &str) -> bool {
+        self.batteries.is_empty()
+            || self
+                .batteries
+                .iter()
+                .any(|battery| battery.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Somewhere battery readings come from.
+pub(crate) trait PowerSource: Send + Sync {
+    fn read(&self, options: &ReadOptions) -> Boxed<Result<Reading>>;
+
+    /// Wakes `refresh` whenever the batteries change, for sources that can
+    /// tell; those are only polled for heartbeats until the returned future
+    /// ends.
+    fn watch(&self, _refresh: Arc<Notify>, _interval: Duration) -> Option<Boxed<Result<()>>> {
+        None
+    }
+
+    /// What the backend reports before any normalizing, for `--debug-topic`.
+    fn raw(&self) -> Boxed<Result<serde_json::Value>> {
+        Box::pin(future::ready(Err(anyhow!("no raw data to publish"))))
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Default)]
+pub(crate) enum Backend {
+    /// Use the first backend that finds a battery: UPower, then the battery
+    /// crate, then sysfs on Linux
+    #[default]
+    Auto,
+    /// Poll the OS through the battery crate
+    Battery,
+    /// Read UPower over D-Bus and update as soon as it reports a change
+    #[cfg(target_os = "linux")]
+    Upower,
+    /// Read /sys/class/power_supply directly, without the battery crate
+    #[cfg(target_os = "linux")]
+    Sysfs,
+    /// The battery crate plus the AC line status from the Win32 power API
+    #[cfg(windows)]
+    Windows,
+}
+
+/// The backends `auto` tries, in order.
+const CHAIN: &[Backend] = &[
+    #[cfg(target_os = "linux")]
+    Backend::Upower,
+    #[cfg(windows)]
+    Backend::Windows,
+    Backend::Battery,
+    #[cfg(target_os = "linux")]
+    Backend::Sysfs,
+];
+
+impl Backend {
+    /// The name the backend goes by on the command line.
+    pub(crate) fn name(self) -> String {
+        self.to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn source(self) -> Box<dyn PowerSource> {
+        match self {
+            Backend::Auto => Box::new(Chain::new(
+                CHAIN
+                    .iter()
+                    .map(|backend| (backend.name(), backend.source()))
+                    .collect(),
+            )),
+            Backend::Battery => Box::new(BatteryCrate),
+            #[cfg(target_os = "linux")]
+            Backend::Upower => Box::new(Upower),
+            #[cfg(target_os = "linux")]
+            Backend::Sysfs => Box::new(Sysfs),
+            #[cfg(windows)]
+            Backend::Windows => Box::new(Windows),
+        }
+    }
+}
+
+/// Falls back along its backends, `CHAIN` for `auto`, whenever one fails or
+/// finds no battery, and records the one that read the battery in the
+/// attributes.
+struct Chain {
+    /// The backends to try in order, with their names.
+    links: Arc<Vec<(String, Box<dyn PowerSource>)>>,
+    /// The index of the backend that answered the first successful read.
+    answered: Arc<Mutex<Option<usize>>>,
+    first_read: Arc<Notify>,
+}
+
+impl Chain {
+    fn new(links: Vec<(String, Box<dyn PowerSource>)>) -> Chain {
+        Chain {
+            links: Arc::new(links),
+            answered: Arc::default(),
+            first_read: Arc::default(),
+        }
+    }
+}
+
+impl PowerSource for Chain {
+    fn read(&self, options: &ReadOptions) -> Boxed<Result<Reading>> {
+        let options = options.clone();
+        let links = self.links.clone();
+        let answered = self.answered.clone();
+        let first_read = self.first_read.clone();
+        Box::pin(async move {
+            let mut errors = Vec::new();
+            for (i, (name, source)) in links.iter().enumerate() {
+                match source.read(&options).await {
+                    Ok((info, mut attributes)) => {
+                        attributes.backend = Some(name.clone());
+                        if let Ok(mut answered) = answered.lock() {
+                            if answered.is_none() {
+                                *answered = Some(i);
+                                first_read.notify_one();
+                            }
+                        }
+                        return Ok((info, attributes));
+                    }
+                    Err(e) => errors.push(format!("{}: {:#}", name, e)),
+                }
+            }
+            Err(anyhow!(
+                "no backend found a battery ({})",
+                errors.join("; ")
+            ))
+        })
+    }
+
+    /// Watches with the backend that answered the first read, if it can, and
+    /// ends right away otherwise so the battery is polled, e.g. when sysfs
+    /// stood in for a missing UPower.
+    fn watch(&self, refresh: Arc<Notify>, interval: Duration) -> Option<Boxed<Result<()>>> {
+        let links = self.links.clone();
+        let answered = self.answered.clone();
+        let first_read = self.first_read.clone();
+        Some(Box::pin(async move {
+            first_read.notified().await;
+            let answered = answered.lock().ok().and_then(|answered| *answered);
+            match answered.and_then(|i| links[i].1.watch(refresh, interval)) {
+                Some(watch) => watch.await,
+                None => Ok(()),
+            }
+        }))
+    }
+}
+
+/// Reports the machine as running on AC, for desktops without a battery:
+/// without an adapter in sysfs it can only be on mains.
+pub(crate) struct AcOnly;
+
+impl PowerSource for AcOnly {
+    fn read(&self, _options: &ReadOptions) -> Boxed<Result<Reading>> {
+        let mut info = ChargeInfo::unknown();
+        #[cfg(windows)]
+        let ac_online = crate::windows::ac_online();
+        #[cfg(not(windows))]
+        let ac_online = crate::sysfs::ac_online();
+        info.ac_connected = Some(ac_online.unwrap_or(true));
+        info.charger_type = crate::sysfs::charger_type()
+            .or_else(|| (ac_online != Some(false)).then(|| String::from("mains")));
+        #[cfg(target_os = "linux")]
+        {
+            info.boot_time = crate::platform::boot_time()
+                .map(|at| crate::units::TimestampFormat::default().at(at));
+        }
+        Box::pin(future::ready(Ok((info, BatteryAttributes::default()))))
+    }
+}
+
+struct BatteryCrate;
+
+impl PowerSource for BatteryCrate {
+    fn read(&self, options: &ReadOptions) -> Boxed<Result<Reading>> {
+        #[cfg(target_os = "macos")]
+        let reading = get_charge_info(options).map(|(mut info, attributes)| {
+            crate::macos::augment(&mut info);
+            (info, attributes)
+        });
+        #[cfg(not(target_os = "macos"))]
+        let reading = get_charge_info(options);
+        Box::pin(future::ready(reading))
+    }
+
+    fn raw(&self) -> Boxed<Result<serde_json::Value>> {
+        Box::pin(future::ready(crate::raw_charge_info()))
+    }
+}
+
+#[cfg(target_os = "linux")]
+struct Upower;
+
+#[cfg(target_os = "linux")]
+impl PowerSource for Upower {
+    fn read(&self, options: &ReadOptions) -> Boxed<Result<Reading>> {
+        Box::pin(crate::upower::read(options.clone()))
+    }
+
+    fn watch(&self, refresh: Arc<Notify>, _interval: Duration) -> Option<Boxed<Result<()>>> {
+        Some(Box::pin(crate::upower::watch(refresh)))
+    }
+
+    fn raw(&self) -> Boxed<Result<serde_json::Value>> {
+        Box::pin(crate::upower::raw())
+    }
+}
+
+#[cfg(target_os = "linux")]
+struct Sysfs;
+
+#[cfg(target_os = "linux")]
+impl PowerSource for Sysfs {
+    fn read(&self, options: &ReadOptions) -> Boxed<Result<Reading>> {
+        Box::pin(future::ready(crate::sysfs::read(options)))
+    }
+
+    fn raw(&self) -> Boxed<Result<serde_json::Value>> {
+        Box::pin(future::ready(crate::sysfs::raw()))
+    }
+}
+
+#[cfg(windows)]
+struct Windows;
+
+#[cfg(windows)]
+impl PowerSource for Windows {
+    fn read(&self, options: &ReadOptions) -> Boxed<Result<Reading>> {
+        let reading = get_charge_info(options).map(|(mut info, attributes)| {
+            info.ac_connected = crate::windows::ac_online();
+            (info, attributes)
+        });
+        Box::pin(future::ready(reading))
+    }
+
+    fn raw(&self) -> Boxed<Result<serde_json::Value>> {
+        Box::pin(future::ready(crate::raw_charge_info()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// A backend that finds a battery or not, and can watch it or not.
+    struct Stub {
+        found: bool,
+        /// Set once the stub's own watch ran; none means it cannot watch.
+        watched: Option<Arc<AtomicBool>>,
+    }
+
+    impl PowerSource for Stub {
+        fn read(&self, _options: &ReadOptions) -> Boxed<Result<Reading>> {
+            Box::pin(future::ready(if self.found {
+                Ok((ChargeInfo::unknown(), BatteryAttributes::default()))
+            } else {
+                Err(anyhow!("no battery found"))
+            }))
+        }
+
+        fn watch(&self, _refresh: Arc<Notify>, _interval: Duration) -> Option<Boxed<Result<()>>> {
+            let watched = self.watched.clone()?;
+            Some(Box::pin(async move {
+                watched.store(true, Ordering::SeqCst);
+                Ok(())
+            }))
+        }
+    }
+
+    fn link(name: &str, found: bool) -> (String, Box<dyn PowerSource>) {
+        (
+            name.to_string(),
+            Box::new(Stub {
+                found,
+                watched: None,
+            }),
+        )
+    }
+
+    fn watch(chain: &Chain) -> Result<()> {
+        let watch = chain
+            .watch(Arc::new(Notify::new()), Duration::from_secs(1))
+            .unwrap();
+        future::block_on(watch)
+    }
+
+    #[test]
+    fn no_batteries_selects_every_battery() {
+        assert!(ReadOptions::default().selects("BAT0"));
+    }
+
+    #[test]
+    fn batteries_are_selected_ignoring_case() {
+        let options = ReadOptions {
+            batteries: vec![String::from("bat1")],
+            ..ReadOptions::default()
+        };
+        assert!(options.selects("BAT1"));
+        assert!(options.selects("bat1"));
+        assert!(!options.selects("BAT0"));
+    }
+
+    #[test]
+    fn chain_falls_back_past_failing_backends() {
+        let chain = Chain::new(vec![
+            link("first", false),
+            link("second", true),
+            link("third", true),
+        ]);
+        let (_, attributes) = future::block_on(chain.read(&ReadOptions::default())).unwrap();
+        assert_eq!(attributes.backend.as_deref(), Some("second"));
+    }
+
+    #[test]
+    fn chain_reports_why_every_backend_failed() {
+        let chain = Chain::new(vec![link("first", false), link("second", false)]);
+        let Err(e) = future::block_on(chain.read(&ReadOptions::default())) else {
+            panic!("no backend found a battery, yet the chain did");
+        };
+        assert_eq!(
+            e.to_string(),
+            "no backend found a battery (first: no battery found; second: no battery found)"
+        );
+    }
+
+    #[test]
+    fn chain_stops_watching_when_the_answering_backend_cannot() {
+        let watched = Arc::new(AtomicBool::new(false));
+        let chain = Chain::new(vec![
+            (
+                String::from("watching"),
+                Box::new(Stub {
+                    found: false,
+                    watched: Some(watched.clone()),
+                }),
+            ),
+            link("polled", true),
+        ]);
+        future::block_on(chain.read(&ReadOptions::default())).unwrap();
+        assert!(watch(&chain).is_ok());
+        assert!(!watched.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn chain_watches_with_the_answering_backend() {
+        let watched = Arc::new(AtomicBool::new(false));
+        let chain = Chain::new(vec![
+            link("failing", false),
+            (
+                String::from("watching"),
+                Box::new(Stub {
+                    found: true,
+                    watched: Some(watched.clone()),
+                }),
+            ),
+        ]);
+        future::block_on(chain.read(&ReadOptions::default())).unwrap();
+        assert!(watch(&chain).is_ok());
+        assert!(watched.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,472 @@
+use crate::{
+    peripherals::{self, Peripheral, PeripheralState},
+    source::ReadOptions,
+    BatteryAttributes, ChargeInfo,
+};
+use anyhow::{bail, Result};
+use battery::State;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+const POWER_SUPPLY: &str = "/sys/class/power_supply";
+
+/// Reads a single trimmed attribute of a power supply.
+pub(crate) fn read_attr(dir: &Path, attr: &str) -> Option<String> {
+    fs::read_to_string(dir.join(attr))
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Power supply directories whose `type` attribute is `kind`, e.g. "Battery".
+pub(crate) fn supplies(kind: &str) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(POWER_SUPPLY) else {
+        return Vec::new();
+    };
+    let mut dirs: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|dir| read_attr(dir, "type").as_deref() == Some(kind))
+        .collect();
+    dirs.sort();
+    dirs
+}
+
+/// Finds the sysfs directory of the battery the battery crate reported,
+/// matching on serial number first and model name second. Directories in
+/// `claimed` already belong to another battery and are skipped.
+pub(crate) fn find_battery(
+    serial: Option<&str>,
+    model: Option<&str>,
+    claimed: &[PathBuf],
+) -> Option<PathBuf> {
+    let batteries: Vec<PathBuf> = supplies("Battery")
+        .into_iter()
+        .filter(|dir| !claimed.contains(dir))
+        .collect();
+    let matching = |attr: &str, wanted: Option<&str>| {
+        let wanted = wanted?.trim();
+        batteries
+            .iter()
+            .find(|dir| read_attr(dir, attr).as_deref() == Some(wanted))
+            .cloned()
+    };
+    matching("serial_number", serial)
+        .or_else(|| matching("model_name", model))
+        .or_else(|| match batteries.as_slice() {
+            [only] => Some(only.clone()),
+            _ => None,
+        })
+}
+
+/// The sysfs directory of the power supply the kernel calls `name`.
+pub(crate) fn supply_dir(name: &str) -> PathBuf {
+    Path::new(POWER_SUPPLY).join(name)
+}
+
+/// Lowercased kernel name of a power supply, e.g. "bat0".
+pub(crate) fn name(dir: &Path) -> Option<String> {
+    Some(dir.file_name()?.to_str()?.to_lowercase())
+}
+
+pub(crate) fn cycle_count(dir: &Path) -> Option<u32> {
+    read_attr(dir, "cycle_count")?.parse().ok()
+}
+
+/// Supplies that can power the machine: classic mains adapters, USB-C ports
+/// (type "USB") that laptops charge through and wireless chargers. Supplies
+/// scoped to a peripheral are left out.
+fn adapters() -> Vec<PathBuf> {
+    let mut adapters = supplies("Mains");
+    for kind in ["USB", "Wireless"] {
+        adapters.extend(
+            supplies(kind)
+                .into_iter()
+                .filter(|dir| read_attr(dir, "scope").as_deref() != Some("Device")),
+        );
+    }
+    adapters
+}
+
+/// What the machine is charging from, e.g. "mains", "wireless" or "usb_pd"
+/// for a USB port with its negotiated `usb_type`; `None` while on battery.
+pub(crate) fn charger_type() -> Option<String> {
+    let adapter = adapters()
+        .into_iter()
+        .find(|dir| read_attr(dir, "online").as_deref() == Some("1"))?;
+    let kind = read_attr(&adapter, "type")?.to_lowercase();
+    if kind != "usb" {
+        return Some(kind);
+    }
+    match read_attr(&adapter, "usb_type") {
+        Some(usb_type) if selected(&usb_type) != "Unknown" => {
+            Some(format!("usb_{}", selected(&usb_type).to_lowercase()))
+        }
+        _ => Some(kind),
+    }
+}
+
+pub(crate) fn has_mains() -> bool {
+    !adapters().is_empty()
+}
+
+/// Whether any adapter is online, or `None` without one.
+pub(crate) fn ac_online() -> Option<bool> {
+    let adapters = adapters();
+    if adapters.is_empty() {
+        return None;
+    }
+    Some(
+        adapters
+            .iter()
+            .any(|dir| read_attr(dir, "online").as_deref() == Some("1")),
+    )
+}
+
+/// Charge level in percent below which the firmware starts charging again.
+pub(crate) fn charge_start_threshold(dir: &Path) -> Option<u8> {
+    read_attr(dir, "charge_control_start_threshold")?
+        .parse()
+        .ok()
+}
+
+/// Batteries of the machine itself, leaving out those of peripherals.
+fn system_batteries() -> Vec<PathBuf> {
+    supplies("Battery")
+        .into_iter()
+        .filter(|dir| read_attr(dir, "scope").as_deref() != Some("Device"))
+        .collect()
+}
+
+/// Batteries of HID devices such as game controllers, which sit on the
+/// `hid` bus and are scoped to a device rather than the machine.
+pub(crate) fn hid_peripherals() -> Vec<Peripheral> {
+    let mut found = Vec::new();
+    for dir in supplies("Battery") {
+        let on_hid = fs::read_link(dir.join("device/subsystem"))
+            .is_ok_and(|subsystem| subsystem.ends_with("hid"));
+        if read_attr(&dir, "scope").as_deref() != Some("Device") || !on_hid {
+            continue;
+        }
+        let (Some(kernel_name), Some(percentage)) =
+            (name(&dir), read_scaled(&dir, "capacity", 1.0))
+        else {
+            continue;
+        };
+        found.push(Peripheral {
+            id: peripherals::id("hid", &kernel_name),
+            name: read_attr(&dir, "model_name").unwrap_or_else(|| kernel_name.clone()),
+            state: PeripheralState {
+                percentage,
+                status: read_attr(&dir, "status").map(|status| status.to_lowercase()),
+            },
+        });
+    }
+    found
+}
+
+/// Whether the firmware can tell us about a critical battery at all.
+pub(crate) fn has_critical_alarm() -> bool {
+    system_batteries()
+        .iter()
+        .any(|dir| dir.join("alarm").exists() || dir.join("capacity_level").exists())
+}
+
+/// Batteries the firmware itself declares critical, with why: its
+/// `capacity_level` says so, or the charge dropped to the ACPI `alarm` level.
+pub(crate) fn critical_batteries() -> Vec<(String, &'static str)> {
+    let mut critical = Vec::new();
+    for (index, dir) in system_batteries().iter().enumerate() {
+        let name = name(dir).unwrap_or_else(|| format!("bat{}", index));
+        let number = |attr: &str| read_attr(dir, attr)?.parse::<u64>().ok();
+        // The alarm is in the unit the battery reports its charge in.
+        let below_alarm = number("alarm")
+            .filter(|alarm| *alarm > 0)
+            .is_some_and(|alarm| {
+                number("energy_now")
+                    .or_else(|| number("charge_now"))
+                    .is_some_and(|now| now <= alarm)
+            });
+        if read_attr(dir, "capacity_level").as_deref() == Some("Critical") {
+            critical.push((name, "capacity_level"));
+        } else if below_alarm {
+            critical.push((name, "alarm"));
+        }
+    }
+    critical
+}
+
+pub(crate) fn has_capacity_level() -> bool {
+    supplies("Battery")
+        .iter()
+        .any(|dir| dir.join("capacity_level").exists())
+}
+
+pub(crate) fn has_charge_start_threshold() -> bool {
+    supplies("Battery")
+        .iter()
+        .any(|dir| dir.join("charge_control_start_threshold").exists())
+}
+
+/// The highest `input_current_limit` (A) or `input_voltage_limit` (V) of the
+/// online adapters, which shows e.g. a USB-PD charger stuck on 5V.
+pub(crate) fn input_limit(attr: &str) -> Option<f32> {
+    adapters()
+        .iter()
+        .filter(|dir| read_attr(dir, "online").as_deref() == Some("1"))
+        .filter_map(|dir| read_scaled(dir, attr, 1e-6))
+        .reduce(f32::max)
+}
+
+pub(crate) fn has_input_limit(attr: &str) -> bool {
+    adapters().iter().any(|dir| dir.join(attr).exists())
+}
+
+/// What the firmware does while on AC, e.g. "auto" or "inhibit-charge".
+pub(crate) fn charge_behaviour(dir: &Path) -> Option<String> {
+    read_attr(dir, "charge_behaviour").map(|value| selected(&value).to_string())
+}
+
+/// The behaviours the batteries offer, e.g. "auto", "inhibit-charge" and
+/// "force-discharge"; empty when the firmware cannot change it.
+pub(crate) fn charge_behaviours() -> Vec<String> {
+    supplies("Battery")
+        .iter()
+        .find_map(|dir| read_attr(dir, "charge_behaviour"))
+        .map(|value| {
+            value
+                .split_whitespace()
+                .map(|choice| choice.trim_matches(['[', ']']).to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Sets the charge behaviour of every battery that supports one, which
+/// usually requires running as root.
+pub(crate) fn set_charge_behaviour(value: &str) -> io::Result<()> {
+    for dir in supplies("Battery") {
+        let attr = dir.join("charge_behaviour");
+        if attr.exists() {
+            fs::write(attr, value)?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn charge_end_threshold(dir: &Path) -> Option<u8> {
+    read_attr(dir, "charge_control_end_threshold")?.parse().ok()
+}
+
+pub(crate) fn has_charge_end_threshold() -> bool {
+    supplies("Battery")
+        .iter()
+        .any(|dir| dir.join("charge_control_end_threshold").exists())
+}
+
+/// Sets the charge end threshold of every battery that supports one, which
+/// usually requires running as root. Nothing is written unless the value is
+/// a percentage above every battery's start threshold.
+pub(crate) fn set_charge_end_threshold(value: u8) -> io::Result<()> {
+    if !(1..=100).contains(&value) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is outside 1-100", value),
+        ));
+    }
+    let dirs: Vec<PathBuf> = supplies("Battery")
+        .into_iter()
+        .filter(|dir| dir.join("charge_control_end_threshold").exists())
+        .collect();
+    if let Some(start) = dirs
+        .iter()
+        .filter_map(|dir| charge_start_threshold(dir))
+        .max()
+    {
+        if value <= start {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is not above the charge start threshold of {}",
+                    value, start
+                ),
+            ));
+        }
+    }
+    for dir in dirs {
+        fs::write(dir.join("charge_control_end_threshold"), value.to_string())?;
+    }
+    Ok(())
+}
+
+/// A numeric attribute scaled by `factor`, e.g. 1e-6 for the µ-units the
+/// kernel reports.
+fn read_scaled(dir: &Path, attr: &str, factor: f32) -> Option<f32> {
+    let value: f32 = read_attr(dir, attr)?.parse().ok()?;
+    Some(value * factor)
+}
+
+/// The selected choice of a multiple-choice attribute like
+/// "[auto] inhibit-charge force-discharge", or the value itself.
+fn selected(value: &str) -> &str {
+    value
+        .split_whitespace()
+        .find_map(|choice| choice.strip_prefix('[')?.strip_suffix(']'))
+        .unwrap_or(value)
+}
+
+fn state(status: Option<&str>) -> State {
+    match status {
+        Some("Charging") => State::Charging,
+        Some("Discharging") => State::Discharging,
+        Some("Full") => State::Full,
+        Some("Empty") => State::Empty,
+        _ => State::Unknown,
+    }
+}
+
+/// Names the kernel's `technology` the way the battery crate does.
+fn technology(technology: &str) -> String {
+    String::from(match technology {
+        "Li-ion" => "lithium-ion",
+        "Li-poly" => "lithium-polymer",
+        "LiFe" => "lithium-iron-phosphate",
+        "NiCd" => "nickel-cadmium",
+        "NiMH" => "nickel-metal-hydride",
+        _ => "unknown",
+    })
+}
+
+/// Energy in Wh of e.g. "full" or "full_design", read from `energy_<name>` or
+/// converted from `charge_<name>` in µAh with the design voltage on packs that
+/// only report charge.
+pub(crate) fn energy(dir: &Path, name: &str) -> Option<f32> {
+    let positive = |value: &f32| *value > 0.0;
+    read_scaled(dir, &format!("energy_{}", name), 1e-6)
+        .filter(positive)
+        .or_else(|| {
+            let design_voltage = read_scaled(dir, "voltage_min_design", 1e-6)
+                .or_else(|| read_scaled(dir, "voltage_now", 1e-6))?;
+            Some(read_scaled(dir, &format!("charge_{}", name), 1e-6)? * design_voltage)
+        })
+        .filter(positive)
+}
+
+/// Reads one battery directory.
+fn read_battery(dir: &Path) -> ChargeInfo {
+    let voltage = read_scaled(dir, "voltage_now", 1e-6).unwrap_or_default();
+    let energy_full = energy(dir, "full").unwrap_or_default();
+    let energy_full_design = energy(dir, "full_design").unwrap_or_default();
+    let energy_now = energy(dir, "now");
+    let percentage = read_scaled(dir, "capacity", 1.0)
+        .or_else(|| Some(energy_now? / energy_full * 100.0).filter(|p| p.is_finite()))
+        .unwrap_or_default();
+    let energy_rate = read_scaled(dir, "power_now", 1e-6)
+        .or_else(|| Some(read_scaled(dir, "current_now", 1e-6)? * voltage))
+        .unwrap_or_default()
+        .abs();
+    ChargeInfo {
+        percentage,
+        state: state(read_attr(dir, "status").as_deref()),
+        energy_rate,
+        energy_now: energy_now.unwrap_or_default(),
+        energy_full,
+        energy_full_design,
+        voltage,
+        health: (energy_full_design > 0.0).then(|| energy_full / energy_full_design * 100.0),
+        cycle_count: cycle_count(dir),
+        time_to_target: None,
+        energy_rate_smoothed: None,
+        time_remaining: None,
+        time_remaining_confidence: None,
+        needs_calibration: None,
+        energy_full_spread: None,
+        ac_connected: None,
+        charger_type: None,
+        input_current_limit: None,
+        input_voltage_limit: None,
+        power_profile: None,
+        lid_closed: None,
+        boot_time: None,
+        charge_start_threshold: charge_start_threshold(dir),
+        charge_end_threshold: charge_end_threshold(dir),
+        capacity_level: read_attr(dir, "capacity_level"),
+        charge_behaviour: charge_behaviour(dir),
+        current: None,
+        power: None,
+        condition: None,
+        vendor: read_attr(dir, "manufacturer"),
+        model: read_attr(dir, "model_name"),
+        serial_number: read_attr(dir, "serial_number"),
+        technology: read_attr(dir, "technology").map(|value| technology(&value)),
+        batteries: BTreeMap::new(),
+    }
+}
+
+/// Every readable attribute of every power supply, untouched, keyed by
+/// supply and attribute name, for `--debug-topic`.
+pub(crate) fn raw() -> Result<serde_json::Value> {
+    let mut raw = serde_json::Map::new();
+    for entry in fs::read_dir(POWER_SUPPLY)?.flatten() {
+        let dir = entry.path();
+        let Some(supply) = name(&dir) else {
+            continue;
+        };
+        let mut attrs = serde_json::Map::new();
+        for attr in fs::read_dir(&dir)?.flatten() {
+            let is_file = attr.file_type().is_ok_and(|kind| kind.is_file());
+            let attr_name = attr.file_name().to_string_lossy().into_owned();
+            if !is_file || attr_name == "uevent" {
+                continue;
+            }
+            if let Some(value) = read_attr(&dir, &attr_name) {
+                attrs.insert(attr_name, serde_json::Value::from(value));
+            }
+        }
+        raw.insert(supply, serde_json::Value::Object(attrs));
+    }
+    Ok(serde_json::Value::Object(raw))
+}
+
+/// Reads the system batteries straight from sysfs, like `get_charge_info`
+/// does through the battery crate. Batteries of peripherals are skipped.
+pub(crate) fn read(options: &ReadOptions) -> Result<(ChargeInfo, BatteryAttributes)> {
+    let dirs = system_batteries();
+    if dirs.is_empty() && fs::metadata(POWER_SUPPLY).is_err() {
+        bail!("{} is not available", POWER_SUPPLY);
+    }
+    let mut batteries = BTreeMap::new();
+    let mut attributes = None;
+    for (index, dir) in dirs.iter().enumerate() {
+        let name = name(dir).unwrap_or_else(|| format!("bat{}", index));
+        if !options.selects(&name) {
+            continue;
+        }
+        let info = read_battery(dir);
+        attributes.get_or_insert_with(|| BatteryAttributes {
+            vendor: info.vendor.clone(),
+            model: info.model.clone(),
+            serial_number: info.serial_number.clone(),
+            technology: info.technology.clone(),
+            cycle_count: info.cycle_count,
+            temperature: read_scaled(dir, "temp", 0.1),
+            charge_start_threshold: info.charge_start_threshold,
+            charge_end_threshold: info.charge_end_threshold,
+            backend: None,
+        });
+        batteries.insert(name, info);
+    }
+    if batteries.is_empty() {
+        bail!("no battery found");
+    }
+    let mut info = ChargeInfo::combine(&batteries.values().collect::<Vec<_>>(), options.aggregate);
+    info.ac_connected = ac_online();
+    info.charger_type = charger_type();
+    info.input_current_limit = input_limit("input_current_limit");
+    info.input_voltage_limit = input_limit("input_voltage_limit");
+    info.batteries = batteries;
+    Ok((info, attributes.unwrap_or_default()))
+}
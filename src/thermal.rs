@@ -0,0 +1,68 @@
+use crate::sysfs::read_attr;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+const THERMAL: &str = "/sys/class/thermal";
+const HWMON: &str = "/sys/class/hwmon";
+
+/// Temperature inputs in millidegrees Celsius with the name they go by: the
+/// `type` of a thermal zone, or the label (else the driver name) of an hwmon
+/// input.
+fn inputs() -> Vec<(String, PathBuf)> {
+    let dirs = |root: &str| -> Vec<PathBuf> {
+        let Ok(entries) = fs::read_dir(root) else {
+            return Vec::new();
+        };
+        let mut dirs: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+        dirs.sort();
+        dirs
+    };
+    let mut inputs = Vec::new();
+    for zone in dirs(THERMAL) {
+        if let Some(kind) = read_attr(&zone, "type") {
+            inputs.push((kind, zone.join("temp")));
+        }
+    }
+    for hwmon in dirs(HWMON) {
+        let driver = read_attr(&hwmon, "name").unwrap_or_default();
+        for input in dirs(&hwmon.to_string_lossy()) {
+            let Some(file) = input.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            let Some(index) = file
+                .strip_prefix("temp")
+                .and_then(|f| f.strip_suffix("_input"))
+            else {
+                continue;
+            };
+            let label = read_attr(&hwmon, &format!("temp{}_label", index));
+            inputs.push((label.unwrap_or_else(|| driver.clone()), input));
+        }
+    }
+    inputs
+}
+
+fn read_millidegrees(path: &Path) -> Option<f32> {
+    let value: f32 = fs::read_to_string(path).ok()?.trim().parse().ok()?;
+    Some(value / 1000.0)
+}
+
+/// Battery temperature in degrees Celsius for machines whose battery does not
+/// report one. `sensor` is a thermal zone type or hwmon label (case
+/// insensitive), or the path of an input; without it the first input whose
+/// name mentions a battery is used.
+pub(crate) fn battery_temperature(sensor: Option<&str>) -> Option<f32> {
+    if let Some(path) = sensor.filter(|sensor| sensor.starts_with('/')) {
+        return read_millidegrees(Path::new(path));
+    }
+    let wanted = |name: &str| match sensor {
+        Some(sensor) => name.eq_ignore_ascii_case(sensor),
+        None => name.to_lowercase().contains("bat"),
+    };
+    inputs()
+        .into_iter()
+        .filter(|(name, _)| wanted(name))
+        .find_map(|(_, path)| read_millidegrees(&path))
+}
@@ -0,0 +1,192 @@
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use clap::ValueEnum;
+use serde::{Serialize, Serializer};
+
+/// How charge levels and other percentages are published.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Default)]
+pub(crate) enum PercentUnit {
+    /// 0-100
+    #[default]
+    Percent,
+    /// 0-1
+    Fraction,
+}
+
+/// How power is published.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Default)]
+pub(crate) enum PowerUnit {
+    #[default]
+    #[value(name = "W")]
+    Watts,
+    #[value(name = "mW")]
+    Milliwatts,
+}
+
+/// How timestamps, like when the battery was last read, are published.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Default)]
+pub(crate) enum TimestampFormat {
+    /// RFC 3339 in UTC, e.g. `2024-05-01T12:00:00Z`
+    #[default]
+    Rfc3339,
+    /// RFC 3339 in the local time zone, e.g. `2024-05-01T14:00:00+02:00`
+    Local,
+    /// Seconds since the Unix epoch, as a number
+    Unix,
+}
+
+/// How durations, like the time remaining, are published.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Default)]
+pub(crate) enum DurationUnit {
+    #[default]
+    #[value(name = "s")]
+    Seconds,
+    #[value(name = "min")]
+    Minutes,
+}
+
+impl PercentUnit {
+    /// Converts from percent.
+    pub(crate) fn convert(self, value: f32) -> f32 {
+        match self {
+            PercentUnit::Percent => value,
+            PercentUnit::Fraction => value / 100.0,
+        }
+    }
+
+    /// The unit of measurement; a fraction has none.
+    pub(crate) fn unit(self) -> &'static str {
+        match self {
+            PercentUnit::Percent => "%",
+            PercentUnit::Fraction => "",
+        }
+    }
+}
+
+impl PowerUnit {
+    /// Converts from watts.
+    pub(crate) fn convert(self, value: f32) -> f32 {
+        match self {
+            PowerUnit::Watts => value,
+            PowerUnit::Milliwatts => value * 1000.0,
+        }
+    }
+
+    pub(crate) fn unit(self) -> &'static str {
+        match self {
+            PowerUnit::Watts => "W",
+            PowerUnit::Milliwatts => "mW",
+        }
+    }
+}
+
+impl DurationUnit {
+    /// Converts from seconds.
+    pub(crate) fn convert(self, value: f32) -> f32 {
+        match self {
+            DurationUnit::Seconds => value,
+            DurationUnit::Minutes => value / 60.0,
+        }
+    }
+
+    pub(crate) fn unit(self) -> &'static str {
+        match self {
+            DurationUnit::Seconds => "s",
+            DurationUnit::Minutes => "min",
+        }
+    }
+}
+
+impl TimestampFormat {
+    pub(crate) fn at(self, at: DateTime<Utc>) -> Timestamp {
+        Timestamp { at, format: self }
+    }
+
+    /// Home Assistant template turning the timestamp `expr` evaluates to into
+    /// a datetime for the timestamp device class.
+    pub(crate) fn template(self, expr: &str) -> String {
+        match self {
+            TimestampFormat::Unix => format!("{{{{ {} | as_datetime(None) }}}}", expr),
+            _ => format!("{{{{ {} }}}}", expr),
+        }
+    }
+}
+
+/// A point in time, serialized in its format.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) struct Timestamp {
+    at: DateTime<Utc>,
+    format: TimestampFormat,
+}
+
+impl Timestamp {
+    pub(crate) fn now(format: TimestampFormat) -> Timestamp {
+        format.at(Utc::now())
+    }
+
+    pub(crate) fn in_format(self, format: TimestampFormat) -> Timestamp {
+        format.at(self.at)
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.format {
+            TimestampFormat::Rfc3339 => {
+                serializer.serialize_str(&self.at.to_rfc3339_opts(SecondsFormat::Secs, true))
+            }
+            TimestampFormat::Local => serializer.serialize_str(
+                &self
+                    .at
+                    .with_timezone(&Local)
+                    .to_rfc3339_opts(SecondsFormat::Secs, false),
+            ),
+            TimestampFormat::Unix => serializer.serialize_i64(self.at.timestamp()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn units_convert_from_the_base_unit() {
+        assert_eq!(PercentUnit::Percent.convert(42.0), 42.0);
+        assert_eq!(PercentUnit::Fraction.convert(50.0), 0.5);
+        assert_eq!(PercentUnit::Fraction.unit(), "");
+        assert_eq!(PowerUnit::Milliwatts.convert(1.5), 1500.0);
+        assert_eq!(PowerUnit::Milliwatts.unit(), "mW");
+        assert_eq!(DurationUnit::Minutes.convert(90.0), 1.5);
+        assert_eq!(DurationUnit::Seconds.unit(), "s");
+    }
+
+    #[test]
+    fn timestamps_serialize_in_their_format() {
+        let at = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let json = |format: TimestampFormat| serde_json::to_string(&format.at(at)).unwrap();
+        assert_eq!(json(TimestampFormat::Rfc3339), r#""2024-05-01T12:00:00Z""#);
+        assert_eq!(json(TimestampFormat::Unix), "1714564800");
+        let local: DateTime<chrono::FixedOffset> =
+            DateTime::parse_from_rfc3339(json(TimestampFormat::Local).trim_matches('"')).unwrap();
+        assert_eq!(local, at);
+        assert!(
+            TimestampFormat::Rfc3339
+                .at(at)
+                .in_format(TimestampFormat::Unix)
+                == TimestampFormat::Unix.at(at)
+        );
+    }
+
+    #[test]
+    fn unix_templates_convert_to_a_datetime() {
+        assert_eq!(
+            TimestampFormat::Rfc3339.template("value_json.last_updated"),
+            "{{ value_json.last_updated }}"
+        );
+        assert_eq!(
+            TimestampFormat::Unix.template("value_json.last_updated"),
+            "{{ value_json.last_updated | as_datetime(None) }}"
+        );
+    }
+}
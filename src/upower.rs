@@ -0,0 +1,280 @@
+use crate::{
+    peripherals::{self, Peripheral, PeripheralState},
+    source::ReadOptions,
+    sysfs, BatteryAttributes, ChargeInfo,
+};
+use anyhow::{bail, Context, Result};
+use battery::State;
+use futures_lite::StreamExt;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+use tokio::sync::Notify;
+use zbus::{
+    message::Type,
+    zvariant::{OwnedObjectPath, OwnedValue},
+    Connection, MatchRule, MessageStream, Proxy,
+};
+
+const SERVICE: &str = "org.freedesktop.UPower";
+const PATH: &str = "/org/freedesktop/UPower";
+const DEVICE: &str = "org.freedesktop.UPower.Device";
+
+/// UPower's `Type` of a device.
+const LINE_POWER: u32 = 1;
+const BATTERY: u32 = 2;
+
+fn state(state: u32) -> State {
+    match state {
+        1 | 5 => State::Charging,
+        2 | 6 => State::Discharging,
+        3 => State::Empty,
+        4 => State::Full,
+        _ => State::Unknown,
+    }
+}
+
+/// Names UPower's `Technology` the way the battery crate does.
+fn technology(technology: u32) -> &'static str {
+    match technology {
+        1 => "lithium-ion",
+        2 => "lithium-polymer",
+        3 => "lithium-iron-phosphate",
+        4 => "lead-acid",
+        5 => "nickel-cadmium",
+        6 => "nickel-metal-hydride",
+        _ => "unknown",
+    }
+}
+
+async fn devices(conn: &Connection) -> Result<Vec<Proxy<'static>>> {
+    let upower = Proxy::new(conn, SERVICE, PATH, SERVICE).await?;
+    let paths: Vec<OwnedObjectPath> = upower.call("EnumerateDevices", &()).await?;
+    let mut devices = Vec::new();
+    for path in paths {
+        devices.push(Proxy::new_owned(conn.clone(), SERVICE, path, DEVICE).await?);
+    }
+    Ok(devices)
+}
+
+/// Reads a property, treating one the UPower version lacks as unset.
+async fn property<T>(device: &Proxy<'_>, name: &str) -> Option<T>
+where
+    T: TryFrom<zbus::zvariant::OwnedValue>,
+    T::Error: Into<zbus::Error>,
+{
+    device.get_property(name).await.ok()
+}
+
+async fn text(device: &Proxy<'_>, name: &str) -> Option<String> {
+    property::<String>(device, name)
+        .await
+        .filter(|value| !value.is_empty())
+}
+
+/// Reads the system batteries from UPower, like `get_charge_info` does from
+/// the battery crate.
+pub(crate) async fn read(options: ReadOptions) -> Result<(ChargeInfo, BatteryAttributes)> {
+    let conn = Connection::system()
+        .await
+        .context("connecting to the system bus")?;
+    let mut batteries = BTreeMap::new();
+    let mut attributes = BTreeMap::new();
+    let mut adapters = Vec::new();
+    for device in devices(&conn).await.context("listing UPower devices")? {
+        let kind: u32 = property(&device, "Type").await.unwrap_or_default();
+        if kind == LINE_POWER {
+            adapters.push(property(&device, "Online").await.unwrap_or(false));
+            continue;
+        }
+        let power_supply: bool = property(&device, "PowerSupply").await.unwrap_or(false);
+        if kind != BATTERY || !power_supply {
+            continue;
+        }
+        let float = |name| property::<f64>(&device, name);
+        let energy_full = float("EnergyFull").await.unwrap_or_default() as f32;
+        let energy_full_design = float("EnergyFullDesign").await.unwrap_or_default() as f32;
+        let native_path = text(&device, "NativePath").await;
+        let name = native_path
+            .as_deref()
+            .map(|path| path.to_lowercase())
+            .unwrap_or_else(|| format!("bat{}", batteries.len()));
+        if !options.selects(&name) {
+            continue;
+        }
+        // UPower reports -1 when it cannot count cycles, though sysfs often can.
+        let cycle_count = match property::<i32>(&device, "ChargeCycles").await {
+            Some(cycles) if cycles > 0 => u32::try_from(cycles).ok(),
+            _ => native_path
+                .as_deref()
+                .and_then(|path| sysfs::cycle_count(&sysfs::supply_dir(path))),
+        };
+        let threshold_supported: bool = property(&device, "ChargeThresholdSupported")
+            .await
+            .unwrap_or(false);
+        let threshold = |name| property::<u32>(&device, name);
+        let (charge_start_threshold, charge_end_threshold) = if threshold_supported {
+            (
+                threshold("ChargeStartThreshold").await,
+                threshold("ChargeEndThreshold").await,
+            )
+        } else {
+            (None, None)
+        };
+        let percent = |value: u32| u8::try_from(value).ok();
+        let charge_start_threshold = charge_start_threshold.and_then(percent);
+        let charge_end_threshold = charge_end_threshold.and_then(percent);
+        let technology = property::<u32>(&device, "Technology")
+            .await
+            .map(|value| String::from(technology(value)));
+        let info = ChargeInfo {
+            percentage: float("Percentage").await.unwrap_or_default() as f32,
+            state: state(property(&device, "State").await.unwrap_or_default()),
+            energy_rate: float("EnergyRate").await.unwrap_or_default() as f32,
+            energy_now: float("Energy").await.unwrap_or_default() as f32,
+            energy_full,
+            energy_full_design,
+            voltage: float("Voltage").await.unwrap_or_default() as f32,
+            health: (energy_full_design > 0.0).then(|| energy_full / energy_full_design * 100.0),
+            cycle_count,
+            time_to_target: None,
+            energy_rate_smoothed: None,
+            time_remaining: None,
+            time_remaining_confidence: None,
+            needs_calibration: None,
+            energy_full_spread: None,
+            ac_connected: None,
+            charger_type: None,
+            input_current_limit: None,
+            input_voltage_limit: None,
+            power_profile: None,
+            lid_closed: None,
+            boot_time: None,
+            charge_start_threshold,
+            charge_end_threshold,
+            capacity_level: native_path
+                .as_deref()
+                .and_then(|path| sysfs::read_attr(&sysfs::supply_dir(path), "capacity_level")),
+            charge_behaviour: native_path
+                .as_deref()
+                .and_then(|path| sysfs::charge_behaviour(&sysfs::supply_dir(path))),
+            current: None,
+            power: None,
+            condition: None,
+            vendor: text(&device, "Vendor").await,
+            model: text(&device, "Model").await,
+            serial_number: text(&device, "Serial").await,
+            technology: technology.clone(),
+            batteries: BTreeMap::new(),
+        };
+        attributes.insert(
+            name.clone(),
+            BatteryAttributes {
+                vendor: info.vendor.clone(),
+                model: info.model.clone(),
+                serial_number: info.serial_number.clone(),
+                technology,
+                cycle_count,
+                temperature: float("Temperature")
+                    .await
+                    .filter(|t| *t != 0.0)
+                    .map(|t| t as f32),
+                charge_start_threshold: info.charge_start_threshold,
+                charge_end_threshold: info.charge_end_threshold,
+                backend: None,
+            },
+        );
+        batteries.insert(name, info);
+    }
+    if batteries.is_empty() {
+        bail!("no battery found");
+    }
+    let mut info = ChargeInfo::combine(&batteries.values().collect::<Vec<_>>(), options.aggregate);
+    info.ac_connected = (!adapters.is_empty()).then(|| adapters.iter().any(|online| *online));
+    info.charger_type = sysfs::charger_type();
+    info.input_current_limit = sysfs::input_limit("input_current_limit");
+    info.input_voltage_limit = sysfs::input_limit("input_voltage_limit");
+    info.batteries = batteries;
+    Ok((info, attributes.into_values().next().unwrap_or_default()))
+}
+
+/// Every property of every UPower device as UPower reports it, keyed by
+/// object path, for `--debug-topic`.
+pub(crate) async fn raw() -> Result<serde_json::Value> {
+    let conn = Connection::system()
+        .await
+        .context("connecting to the system bus")?;
+    let mut raw = serde_json::Map::new();
+    for device in devices(&conn).await.context("listing UPower devices")? {
+        let properties = Proxy::new(
+            &conn,
+            SERVICE,
+            device.path().to_owned(),
+            "org.freedesktop.DBus.Properties",
+        )
+        .await?;
+        let values: HashMap<String, OwnedValue> = properties.call("GetAll", &(DEVICE,)).await?;
+        let values = values
+            .into_iter()
+            .map(|(name, value)| (name, serde_json::Value::from(value.to_string())))
+            .collect();
+        raw.insert(device.path().to_string(), serde_json::Value::Object(values));
+    }
+    Ok(serde_json::Value::Object(raw))
+}
+
+/// Battery-powered devices UPower knows about that do not power the system,
+/// like wireless mice, keyboards and game controllers.
+pub(crate) async fn peripherals() -> Result<Vec<Peripheral>> {
+    let conn = Connection::system()
+        .await
+        .context("connecting to the system bus")?;
+    let mut peripherals = Vec::new();
+    for device in devices(&conn).await.context("listing UPower devices")? {
+        let kind: u32 = property(&device, "Type").await.unwrap_or_default();
+        let power_supply: bool = property(&device, "PowerSupply").await.unwrap_or(true);
+        let present: bool = property(&device, "IsPresent").await.unwrap_or(false);
+        if kind == LINE_POWER || power_supply || !present {
+            continue;
+        }
+        let (Some(native_path), Some(percentage)) = (
+            text(&device, "NativePath").await,
+            property::<f64>(&device, "Percentage").await,
+        ) else {
+            continue;
+        };
+        let name = match (text(&device, "Vendor").await, text(&device, "Model").await) {
+            (Some(vendor), Some(model)) => format!("{} {}", vendor, model),
+            (vendor, model) => model.or(vendor).unwrap_or_else(|| native_path.clone()),
+        };
+        peripherals.push(Peripheral {
+            id: peripherals::id("upower", &native_path),
+            name,
+            state: PeripheralState {
+                percentage: percentage as f32,
+                status: None,
+            },
+        });
+    }
+    Ok(peripherals)
+}
+
+/// Wakes `refresh` whenever a UPower device changes, e.g. when the charger is
+/// plugged in, so changes are published without waiting for the next poll.
+pub(crate) async fn watch(refresh: Arc<Notify>) -> Result<()> {
+    let conn = Connection::system()
+        .await
+        .context("connecting to the system bus")?;
+    let rule = MatchRule::builder()
+        .msg_type(Type::Signal)
+        .sender(SERVICE)?
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .build();
+    let mut signals = MessageStream::for_match_rule(rule, &conn, None).await?;
+    while signals.next().await.is_some() {
+        refresh.notify_one();
+    }
+    Ok(())
+}
@@ -0,0 +1,307 @@
+use crate::{
+    client_id, discovery_configs, discovery_messages, mqtt_options, Args, Discovery,
+    DiscoveryDevice, Host, NodeID,
+};
+use rumqttc::{AsyncClient, Event, Packet, QoS};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time;
+
+const SENSOR_DEVICE_CLASSES: &[&str] = &[
+    "apparent_power",
+    "aqi",
+    "atmospheric_pressure",
+    "battery",
+    "carbon_dioxide",
+    "carbon_monoxide",
+    "current",
+    "data_rate",
+    "data_size",
+    "date",
+    "distance",
+    "duration",
+    "energy",
+    "energy_storage",
+    "enum",
+    "frequency",
+    "gas",
+    "humidity",
+    "illuminance",
+    "irradiance",
+    "moisture",
+    "monetary",
+    "power",
+    "power_factor",
+    "pressure",
+    "reactive_power",
+    "signal_strength",
+    "sound_pressure",
+    "speed",
+    "temperature",
+    "timestamp",
+    "voltage",
+    "volume",
+    "volume_storage",
+    "water",
+    "weight",
+];
+
+const BINARY_SENSOR_DEVICE_CLASSES: &[&str] = &[
+    "battery",
+    "battery_charging",
+    "connectivity",
+    "opening",
+    "plug",
+    "power",
+    "problem",
+    "running",
+    "update",
+];
+
+const NUMBER_DEVICE_CLASSES: &[&str] = &[
+    "battery",
+    "current",
+    "duration",
+    "energy",
+    "energy_storage",
+    "power",
+    "temperature",
+    "voltage",
+];
+
+const ENTITY_CATEGORIES: &[&str] = &["config", "diagnostic"];
+
+/// Checks a discovery config against the rules Home Assistant enforces when it
+/// receives one, returning a description of every violation.
+fn validate(discovery: &Discovery) -> Vec<String> {
+    let mut problems = Vec::new();
+    let topic = &discovery.topic;
+    let payload = &discovery.payload;
+
+    let valid_id = |id: &str| {
+        !id.is_empty()
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    };
+    if !valid_id(&topic.object_id) {
+        problems.push(format!(
+            "object_id {:?} must be non-empty and only contain [a-zA-Z0-9_-]",
+            topic.object_id
+        ));
+    }
+    if let NodeID::Is(node_id) = &topic.node_id {
+        if !valid_id(node_id) {
+            problems.push(format!(
+                "node_id {:?} must be non-empty and only contain [a-zA-Z0-9_-]",
+                node_id
+            ));
+        }
+    }
+    if !valid_id(&payload.unique_id) {
+        problems.push(format!(
+            "unique_id {:?} must be non-empty and only contain [a-zA-Z0-9_-]",
+            payload.unique_id
+        ));
+    }
+    if payload.device.is_none() {
+        problems.push(String::from("device is missing"));
+    }
+    if payload.name.is_empty() {
+        problems.push(String::from("name is empty"));
+    }
+    // Buttons only send commands and have no state to read; updates read
+    // their JSON state without a template.
+    let stateful = topic.comp != DiscoveryDevice::Button;
+    let templated = stateful && topic.comp != DiscoveryDevice::Update;
+    if stateful && (payload.state_topic.is_empty() || payload.state_topic.contains(['+', '#'])) {
+        problems.push(format!(
+            "state_topic {:?} must be non-empty and contain no wildcards",
+            payload.state_topic
+        ));
+    }
+    let optional_topics = [
+        ("command_topic", &payload.command_topic),
+        ("availability_topic", &payload.availability_topic),
+        ("json_attributes_topic", &payload.json_attributes_topic),
+    ];
+    for (key, value) in optional_topics {
+        if let Some(value) = value {
+            if value.is_empty() || value.contains(['+', '#']) {
+                problems.push(format!(
+                    "{} {:?} must be non-empty and contain no wildcards",
+                    key, value
+                ));
+            }
+        }
+    }
+    let commanded = matches!(
+        topic.comp,
+        DiscoveryDevice::Number
+            | DiscoveryDevice::Button
+            | DiscoveryDevice::Switch
+            | DiscoveryDevice::Select
+    );
+    if commanded && payload.command_topic.is_none() {
+        problems.push(format!("a {} needs a command_topic", topic.comp));
+    }
+    if templated && payload.value_template.is_empty() {
+        problems.push(String::from("value_template is empty"));
+    }
+    if let Some(device_class) = &payload.device_class {
+        let allowed = match topic.comp {
+            DiscoveryDevice::Sensor => SENSOR_DEVICE_CLASSES,
+            DiscoveryDevice::BinarySensor => BINARY_SENSOR_DEVICE_CLASSES,
+            DiscoveryDevice::Number => NUMBER_DEVICE_CLASSES,
+            DiscoveryDevice::Switch => &["outlet", "switch"],
+            DiscoveryDevice::Update => &["firmware"],
+            DiscoveryDevice::Button
+            | DiscoveryDevice::Select
+            | DiscoveryDevice::Device
+            | DiscoveryDevice::NoneType => &[],
+        };
+        if !allowed.contains(&device_class.as_str()) {
+            problems.push(format!(
+                "device_class {:?} is not valid for a {}",
+                device_class, topic.comp
+            ));
+        }
+    }
+    if payload.device_class.as_deref() == Some("enum") && payload.options.is_none() {
+        problems.push(String::from("an enum sensor needs options"));
+    }
+    if topic.comp == DiscoveryDevice::Select && payload.options.is_none() {
+        problems.push(String::from("a select needs options"));
+    }
+    if let Some(icon) = &payload.icon {
+        let valid = icon
+            .split_once(':')
+            .is_some_and(|(set, name)| !set.is_empty() && !name.is_empty());
+        if !valid {
+            problems.push(format!("icon {:?} must look like \"mdi:laptop\"", icon));
+        }
+    }
+    if let Some(category) = &payload.entity_category {
+        if !ENTITY_CATEGORIES.contains(&category.as_str()) {
+            problems.push(format!("entity_category {:?} is not valid", category));
+        }
+    }
+    problems
+}
+
+/// Publishes a canary on `<topic>/verify` and waits for the broker to deliver
+/// it back to our own subscription.
+async fn round_trip(args: &Args) -> Result<(), String> {
+    let canary_topic = format!("{}/verify", args.topic);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let canary = format!("canary-{}", nanos);
+
+    let options = mqtt_options(args, format!("{}-verify", client_id(args)));
+    let (client, mut eventloop) = AsyncClient::new(options, 10);
+    client
+        .subscribe(&canary_topic, QoS::AtLeastOnce)
+        .await
+        .map_err(|e| format!("subscribe failed: {:?}", e))?;
+    client
+        .publish(&canary_topic, QoS::AtLeastOnce, false, canary.clone())
+        .await
+        .map_err(|e| format!("publish failed: {:?}", e))?;
+
+    let echo = async {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::Publish(p)))
+                    if p.topic == canary_topic && p.payload == canary.as_bytes() =>
+                {
+                    return Ok(());
+                }
+                Ok(_) => (),
+                Err(e) => return Err(format!("connection error: {:?}", e)),
+            }
+        }
+    };
+    let result = match time::timeout(Duration::from_secs(10), echo).await {
+        Ok(result) => result,
+        Err(_) => Err(String::from("broker did not echo the canary within 10s")),
+    };
+    let _ = client.disconnect().await;
+    result
+}
+
+/// Validates every discovery config and prints a line per config, returning
+/// whether all of them passed.
+fn check_configs(args: &Args, host: &Host) -> bool {
+    let mut passed = true;
+    let discoveries = if args.no_discovery {
+        Vec::new()
+    } else {
+        discovery_configs(args, host)
+    };
+    for discovery in discoveries {
+        let problems = validate(&discovery);
+        if problems.is_empty() {
+            println!("[ok]   discovery {}", discovery.topic);
+        } else {
+            passed = false;
+            println!("[fail] discovery {}", discovery.topic);
+            for problem in problems {
+                println!("         {}", problem);
+            }
+        }
+    }
+    passed
+}
+
+/// Validates and prints the discovery messages without connecting to a broker.
+pub(crate) async fn check(args: &Args) -> bool {
+    let host = Host::read(args).await;
+    let passed = check_configs(args, &host);
+    if !args.no_discovery {
+        println!();
+        print_messages(args, &host);
+    }
+    passed
+}
+
+/// Prints the discovery messages by topic, each payload pretty-printed with
+/// its keys sorted, so the output of two configs diffs cleanly.
+pub(crate) async fn print(args: &Args) {
+    print_messages(args, &Host::read(args).await);
+}
+
+fn print_messages(args: &Args, host: &Host) {
+    let mut messages = discovery_messages(args, host);
+    messages.sort_by(|a, b| a.topic.cmp(&b.topic));
+    let rendered: Vec<String> = messages
+        .into_iter()
+        .map(|message| {
+            let payload = serde_json::from_str::<serde_json::Value>(&message.payload)
+                .and_then(|value| serde_json::to_string_pretty(&value))
+                .unwrap_or(message.payload);
+            format!("{}\n{}", message.topic, payload)
+        })
+        .collect();
+    println!("{}", rendered.join("\n\n"));
+}
+
+/// Runs every check and prints a report, returning whether all of them passed.
+pub(crate) async fn run(args: &Args) -> bool {
+    let mut passed = check_configs(args, &Host::read(args).await);
+
+    match round_trip(args).await {
+        Ok(()) => println!("[ok]   round trip via {}:{}", args.hostname, args.port),
+        Err(e) => {
+            passed = false;
+            println!(
+                "[fail] round trip via {}:{}: {}",
+                args.hostname, args.port, e
+            );
+        }
+    }
+
+    let outcome = if passed { "passed" } else { "failed" };
+    println!("verify {}", outcome);
+    passed
+}
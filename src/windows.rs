@@ -0,0 +1,16 @@
+use windows_sys::Win32::System::Power::{GetSystemPowerStatus, SYSTEM_POWER_STATUS};
+
+/// Whether the machine runs on AC power, or `None` when Windows can't tell.
+pub(crate) fn ac_online() -> Option<bool> {
+    // SAFETY: SYSTEM_POWER_STATUS is plain data that GetSystemPowerStatus
+    // fills in.
+    let mut status: SYSTEM_POWER_STATUS = unsafe { std::mem::zeroed() };
+    if unsafe { GetSystemPowerStatus(&mut status) } == 0 {
+        return None;
+    }
+    match status.ACLineStatus {
+        0 => Some(false),
+        1 => Some(true),
+        _ => None,
+    }
+}